@@ -140,6 +140,23 @@ pub enum Error {
     },
     #[snafu(display("Unexpected format for the JSON report for LVM command '{}'", command))]
     UnexpectedReportFormat { command: String },
+    #[snafu(display(
+        "Could not parse size '{}' from LVM command '{}' JSON report: {}",
+        value,
+        command,
+        source
+    ))]
+    InvalidSize {
+        command: String,
+        value: String,
+        source: std::num::ParseIntError,
+    },
+    #[snafu(display("Refusing to shrink LV '{}' from {} to {} bytes", name, current, size))]
+    Shrink {
+        name: String,
+        current: u64,
+        size: u64,
+    },
 }
 
 /// Library specific Result type
@@ -230,6 +247,69 @@ impl Lv {
         format!("/dev/{}/{}", &self.vg.name, &self.name)
     }
 
+    /// Get the Volume Group holding this Logical Volume
+    pub fn vg(&self) -> Vg {
+        self.vg.clone()
+    }
+
+    /// Get the Logical Volume size in bytes
+    pub fn size(&self) -> Result<u64> {
+        debug!(
+            "Getting size for LV '{}' in VG '{}'",
+            &self.name, &self.vg.name
+        );
+
+        let command = "lvs";
+        let path = format!("{}/{}", &self.vg.name, &self.name);
+        let lv_list = command_json::<JsonReportLvs, &[&str], _>(
+            command,
+            Some(&["--units", "b", "--nosuffix", &path]),
+        )?;
+
+        let size = &lv_list
+            .report
+            .first()
+            .context(UnexpectedReportFormat { command })?
+            .lv
+            .first()
+            .context(UnexpectedReportFormat { command })?
+            .lv_size;
+        size.parse::<u64>().context(InvalidSize {
+            command,
+            value: size.clone(),
+        })
+    }
+
+    /// Resize a Logical Volume to the given size in bytes. Only growing is
+    /// supported: fails if the Logical Volume is larger, as shrinking it would
+    /// truncate its content. Does nothing if it already has this size.
+    pub fn resize(&self, size: u64) -> Result<()> {
+        debug!("Resizing LV '{}' to {} bytes", &self.name, size);
+
+        if !Lv::needs_resize(&self.name, self.size()?, size)? {
+            debug!("LV '{}' is already {} bytes", &self.name, size);
+            return Ok(());
+        }
+        let path = format!("{}/{}", &self.vg.name, &self.name);
+        command::<&[&str], _>("lvextend", Some(&["-L", &format!("{}b", size), &path]))?;
+
+        debug!("Resized LV '{}' to {} bytes", &self.name, size);
+        Ok(())
+    }
+
+    /// Check that resizing a Logical Volume from current to size bytes is
+    /// allowed, i.e. that it would not be shrunk, and needed
+    fn needs_resize(name: &str, current: u64, size: u64) -> Result<bool> {
+        if size < current {
+            return Err(Error::Shrink {
+                name: name.to_string(),
+                current,
+                size,
+            });
+        }
+        Ok(size > current)
+    }
+
     /// Rename a Logical Volume
     pub fn rename_to(&self, dest: String) -> Result<Lv> {
         debug!("Renaming LV '{}' to '{}'", &self.name, dest);
@@ -297,6 +377,30 @@ impl Vg {
         Ok(None)
     }
 
+    /// Get the free space in bytes in a Volume Group
+    pub fn free(&self) -> Result<u64> {
+        debug!("Getting free space for VG '{}'", &self.name);
+
+        let command = "vgs";
+        let vg_list = command_json::<JsonReportVgs, &[&str], _>(
+            command,
+            Some(&["--units", "b", "--nosuffix", &self.name]),
+        )?;
+
+        let free = &vg_list
+            .report
+            .first()
+            .context(UnexpectedReportFormat { command })?
+            .vg
+            .first()
+            .context(UnexpectedReportFormat { command })?
+            .vg_free;
+        free.parse::<u64>().context(InvalidSize {
+            command,
+            value: free.clone(),
+        })
+    }
+
     /// List all Logical Volume in a Volume Group
     pub fn list_lv(&self) -> Result<Vec<Lv>> {
         debug!("Listing all available LV for VG '{}'", &self.name);
@@ -381,3 +485,25 @@ impl Vg {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resize_never_shrinks() {
+        assert!(Lv::needs_resize("core_5.0.0", 1 << 30, 2 << 30).unwrap());
+        assert!(!Lv::needs_resize("core_5.0.0", 1 << 30, 1 << 30).unwrap());
+        match Lv::needs_resize("core_5.0.0", 2 << 30, 1 << 30) {
+            Err(Error::Shrink {
+                ref name,
+                current,
+                size,
+            }) => {
+                assert_eq!(name, "core_5.0.0");
+                assert_eq!((current, size), (2 << 30, 1 << 30));
+            }
+            r => panic!("LV shrunk: {:?}", r),
+        }
+    }
+}
@@ -1,33 +1,121 @@
 // SPDX-License-Identifier: LGPL-2.1-or-later
 // Copyright © 2019 ANSSI. All rights reserved.
 
-use minisign::PublicKey;
 use os_release::OsRelease;
 use reqwest::header::{HeaderMap, HeaderValue};
-use reqwest::{Certificate, Client};
+use reqwest::{Certificate, Client, Identity, Proxy, Url};
 use semver::Version;
 use snafu::{OptionExt, ResultExt, Snafu};
 use std::fs;
 use std::fs::File;
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 
-use crate::system::{Kind, Package, System};
+use crate::error::{Classify, ErrorClass};
+use crate::floor;
+use crate::keys;
+use crate::manifest;
+use crate::pin;
+use crate::source;
+use crate::source::Source as _;
+use crate::source::{Http, Local, Pinned, Response};
+use crate::system::{parse_size, Kind, Package, System};
 
 /// Required information to get update from a remote source
 pub struct Remote {
+    /// Mirrors sorted by priority
+    pub mirrors: Vec<Mirror>,
+    pub limits: Limits,
+    pub retry: Retry,
+    /// HTTPS client only accepting pinned server public keys, used instead of
+    /// the shared HTTP client if pins are configured
+    pub(crate) pinned: Option<pin::Client>,
+    pub bandwidth: Bandwidth,
+    /// Whether the updater has been started by the update timer rather than
+    /// by a user, to select the download rate limit
+    pub timer: bool,
+    /// Dry run: nothing is written to the state folder. Revoked keys and
+    /// downgrade overrides are only taken into account for this run.
+    pub dry_run: bool,
+    /// HTTP client shared by all requests to reuse connections
+    client: Client,
+}
+
+/// Settings shared by the HTTP clients: the reqwest client used for all
+/// requests and the HTTPS client used instead if server keys are pinned
+pub(crate) struct Transport {
+    /// PEM encoded root CA (rootca.pem), only read if a mirror is remote
+    pub rootca: Option<Vec<u8>>,
+    /// PEM encoded client private key and certificate chain
+    pub identity: Option<Vec<u8>>,
+    /// Headers sent with every request
+    pub headers: HeaderMap,
+    pub connect_timeout: Duration,
+    pub timeout: Duration,
+    pub proxy: Option<ProxySettings>,
+    /// Remote configuration folder, to report invalid files
+    pub remote: PathBuf,
+}
+
+/// Forward proxy used to reach the update server
+#[derive(Debug, Clone)]
+pub struct ProxySettings {
+    pub url: Url,
+    /// Username and password to authenticate to the proxy
+    pub credentials: Option<(String, String)>,
+    /// Hosts (and their subdomains) reached without the proxy
+    pub no_proxy: Vec<String>,
+}
+
+/// Update server serving the same content as all other mirrors
+#[derive(Debug, Clone)]
+pub struct Mirror {
     pub update_url: String,
     pub dist_url: String,
-    pub rootca: Certificate,
-    pub headers: HeaderMap,
+    pub priority: u32,
+}
+
+/// Download rate limits in bytes per second
+pub struct Bandwidth {
+    /// Limit for runs started by the update timer
+    pub timer: Option<u64>,
+    /// Limit for runs started by a user
+    pub interactive: Option<u64>,
+}
+
+/// How failed requests are retried on each mirror
+pub struct Retry {
+    /// Number of attempts for each mirror
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for each following one
+    pub delay: Duration,
+    /// Maximum delay between two attempts
+    pub max_delay: Duration,
+}
+
+/// Limits applied to every request to protect against misbehaving servers
+pub struct Limits {
+    /// Timeout to establish a connection
+    pub connect_timeout: Duration,
+    /// Timeout for each read or write on a connection
+    pub read_timeout: Duration,
+    /// Maximum size of the version document and of signatures
+    pub max_metadata_size: u64,
+    /// Maximum size of a payload, unless set for its package
+    pub max_payload_size: Option<u64>,
 }
 
 /// Used to parse `config.toml` configuration files
 #[derive(Deserialize, Debug)]
 pub struct TomlConfig {
     os_name: String,
-    core: TomlCore,
-    efiboot: TomlEfiboot,
+    threshold: Option<usize>,
+    core: Option<TomlCore>,
+    efiboot: Option<TomlEfiboot>,
+    #[serde(default)]
+    package: Vec<TomlPackage>,
 }
 
 /// Used to parse `config.toml` configuration files
@@ -43,17 +131,101 @@ pub struct TomlEfiboot {
     destination: String,
 }
 
+/// Used to parse `config.toml` configuration files
+#[derive(Deserialize, Debug)]
+pub struct TomlPackage {
+    name: String,
+    #[serde(rename = "type")]
+    kind: Kind,
+    destination: String,
+    size: Option<String>,
+    #[serde(default)]
+    order: u32,
+    max_size: Option<String>,
+}
+
 /// Used to parse `remote.toml` configuration files
 #[derive(Deserialize, Debug)]
 pub struct TomlRemote {
+    update_url: Option<String>,
+    dist_url: Option<String>,
+    #[serde(default)]
+    mirror: Vec<TomlMirror>,
+    #[serde(default)]
+    limits: TomlLimits,
+    #[serde(default)]
+    retry: TomlRetry,
+    proxy: Option<TomlProxy>,
+    #[serde(default)]
+    pins: Vec<String>,
+    #[serde(default)]
+    bandwidth: TomlBandwidth,
+}
+
+/// Used to parse `remote.toml` configuration files
+#[derive(Deserialize, Debug, Default)]
+pub struct TomlBandwidth {
+    rate: Option<String>,
+    timer_rate: Option<String>,
+    interactive_rate: Option<String>,
+}
+
+/// Used to parse `remote.toml` configuration files
+#[derive(Deserialize, Debug)]
+pub struct TomlProxy {
+    url: String,
+    credentials: Option<PathBuf>,
+    #[serde(default)]
+    no_proxy: Vec<String>,
+}
+
+/// Used to parse `remote.toml` configuration files
+#[derive(Deserialize, Debug)]
+pub struct TomlMirror {
     update_url: String,
     dist_url: String,
+    #[serde(default)]
+    priority: u32,
+}
+
+/// Used to parse `remote.toml` configuration files
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct TomlRetry {
+    attempts: u32,
+    delay: u64,
+    max_delay: u64,
+}
+
+impl Default for TomlRetry {
+    fn default() -> TomlRetry {
+        TomlRetry {
+            attempts: 3,
+            delay: 1,
+            max_delay: 60,
+        }
+    }
 }
 
-/// Used to parse `version.toml` remote configuration files
+/// Used to parse `remote.toml` configuration files
 #[derive(Deserialize, Debug)]
-pub struct TomlVersion {
-    version: String,
+#[serde(default)]
+pub struct TomlLimits {
+    connect_timeout: u64,
+    read_timeout: u64,
+    max_metadata_size: String,
+    max_payload_size: Option<String>,
+}
+
+impl Default for TomlLimits {
+    fn default() -> TomlLimits {
+        TomlLimits {
+            connect_timeout: 30,
+            read_timeout: 60,
+            max_metadata_size: String::from("64k"),
+            max_payload_size: None,
+        }
+    }
 }
 
 #[derive(Debug, Snafu)]
@@ -73,8 +245,6 @@ pub enum Error {
         filename: PathBuf,
         source: toml::de::Error,
     },
-    #[snafu(display("Could not parse received TOML: {}", source))]
-    InvalidRemoteToml { source: toml::de::Error },
     #[snafu(display("Could not parse '{}' as valid version: {}", version, source))]
     InvalidVersion {
         version: String,
@@ -87,29 +257,117 @@ pub enum Error {
         value: String,
         source: reqwest::header::InvalidHeaderValue,
     },
-    #[snafu(display("Could not parse public key from '{}': {}", filename.display(), source))]
-    InvalidPublicKey {
-        filename: PathBuf,
-        source: minisign::PError,
-    },
     #[snafu(display("Could not parse root certificate from '{}': {}", filename.display(), source))]
     InvalidCertificate {
         filename: PathBuf,
         source: reqwest::Error,
     },
-    #[snafu(display("HTTP request failed: {}", source))]
-    HTTP { source: reqwest::Error },
+    #[snafu(display("{}", source))]
+    Source { source: source::Error },
+    #[snafu(display("No package configured in '{}'", filename.display()))]
+    NoPackage { filename: PathBuf },
+    #[snafu(display("Package '{}' is configured more than once", name))]
+    DuplicatePackage { name: String },
+    #[snafu(display(
+        "ESP packages '{}' and '{}' can not share the destination '{}'",
+        first,
+        second,
+        destination
+    ))]
+    DuplicateEsp {
+        first: String,
+        second: String,
+        destination: String,
+    },
+    #[snafu(display("ESP package '{}' must be installed after LV package '{}'", esp, lv))]
+    InvalidOrder { esp: String, lv: String },
+    #[snafu(display("Invalid size '{}' for package '{}'", size, name))]
+    InvalidSize { name: String, size: String },
+    #[snafu(display("Invalid size '{}' for limit '{}'", size, name))]
+    InvalidLimit { name: String, size: String },
+    #[snafu(display("Could not read response from '{}': {}", url, source))]
+    Body { url: String, source: std::io::Error },
+    #[snafu(display("Response from '{}' is larger than {} bytes", url, limit))]
+    TooLarge { url: String, limit: u64 },
+    #[snafu(display("No complete mirror configured in '{}'", filename.display()))]
+    NoMirror { filename: PathBuf },
+    #[snafu(display("Invalid number of attempts in '{}': must be at least 1", filename.display()))]
+    InvalidRetry { filename: PathBuf },
+    #[snafu(display("Invalid proxy URL '{}': {}", url, source))]
+    InvalidProxy {
+        url: String,
+        source: reqwest::UrlError,
+    },
+    #[snafu(display("Could not read 'username:password' from '{}'", filename.display()))]
+    InvalidProxyCredentials { filename: PathBuf },
+    #[snafu(display("Could not parse client certificate and key from '{}': {}", filename.display(), source))]
+    InvalidIdentity {
+        filename: PathBuf,
+        source: reqwest::Error,
+    },
+    #[snafu(display("Client certificate '{}' and key '{}' must be provided together", certificate.display(), key.display()))]
+    IncompleteIdentity { certificate: PathBuf, key: PathBuf },
+    #[snafu(display("{}", source))]
+    Pin { source: pin::Error },
+    #[snafu(display("Could not setup HTTP client: {}", source))]
+    InvalidClient { source: reqwest::Error },
+    #[snafu(display("{}", source))]
+    Manifest { source: manifest::Error },
+    #[snafu(display("{}", source))]
+    Keys { source: keys::Error },
+    #[snafu(display("{}", source))]
+    Floor { source: floor::Error },
 }
 
-impl From<reqwest::Error> for Error {
-    fn from(err: reqwest::Error) -> Error {
-        Error::HTTP { source: err }
+impl From<manifest::Error> for Error {
+    fn from(err: manifest::Error) -> Error {
+        Error::Manifest { source: err }
     }
 }
 
-impl From<toml::de::Error> for Error {
-    fn from(err: toml::de::Error) -> Error {
-        Error::InvalidRemoteToml { source: err }
+impl From<keys::Error> for Error {
+    fn from(err: keys::Error) -> Error {
+        Error::Keys { source: err }
+    }
+}
+
+impl From<floor::Error> for Error {
+    fn from(err: floor::Error) -> Error {
+        Error::Floor { source: err }
+    }
+}
+
+impl From<source::Error> for Error {
+    fn from(err: source::Error) -> Error {
+        Error::Source { source: err }
+    }
+}
+
+impl From<pin::Error> for Error {
+    fn from(err: pin::Error) -> Error {
+        Error::Pin { source: err }
+    }
+}
+
+impl Classify for Error {
+    fn class(&self) -> ErrorClass {
+        match self {
+            Error::Body { .. } | Error::TooLarge { .. } => ErrorClass::Network,
+            Error::Source { source } => source.class(),
+            Error::Pin { source } => source.class(),
+            Error::Manifest { source } => source.class(),
+            Error::Keys { source } => source.class(),
+            Error::Floor { source } => source.class(),
+            _ => ErrorClass::Config,
+        }
+    }
+
+    fn transient(&self) -> bool {
+        match self {
+            Error::Body { .. } => true,
+            Error::Source { source } => source.transient(),
+            _ => false,
+        }
     }
 }
 
@@ -117,14 +375,24 @@ type Result<T> = std::result::Result<T, Error>;
 
 /// Parse the following configuration files from the configuration folder:
 ///   * config.toml
-///   * pubkey
+///   * pubkey and/or keys/*.pub
 ///   * remote.toml
-///   * rootca
+///   * rootca (unless all mirrors are `file://` URLs)
+///   * client.pem & client.key (optional, ignored if all mirrors are
+///     `file://` URLs)
 ///
 /// Retrieve information from the following configuration files:
 ///   * /etc/os-release
 ///   * /etc/machine-id
-pub fn parse(config: PathBuf, remote: PathBuf, tmp: String) -> Result<(System, Remote)> {
+///
+/// Update payloads are downloaded to the `tmp` folder while the state kept
+/// between runs is stored in the `state` folder.
+pub fn parse(
+    config: PathBuf,
+    remote: PathBuf,
+    tmp: String,
+    state: String,
+) -> Result<(System, Remote)> {
     let filename = &config.join("config.toml");
     let mut content = String::new();
     File::open(&filename)
@@ -133,6 +401,7 @@ pub fn parse(config: PathBuf, remote: PathBuf, tmp: String) -> Result<(System, R
         .context(InvalidFile { filename })?;
     let c: TomlConfig = toml::from_str(&content).context(InvalidToml { filename })?;
     debug!("Read {}:\n{:#?}", filename.display(), &c);
+    let packages = packages(c.core, c.efiboot, c.package, filename)?;
 
     // Get current version from /etc/os-release
     let version_id = OsRelease::new()
@@ -149,9 +418,7 @@ pub fn parse(config: PathBuf, remote: PathBuf, tmp: String) -> Result<(System, R
 
     info!("Currently on '{}', version '{}'", c.os_name, version);
 
-    let filename = &config.join("pubkey");
-    let pubkey = PublicKey::from_file(filename).context(InvalidPublicKey { filename })?;
-    debug!("Read public key from {}", filename.display());
+    let keys = keys::Keys::load(&config, c.threshold.unwrap_or(1))?;
 
     let filename = &remote.join("remote.toml");
     let mut content = String::new();
@@ -161,15 +428,29 @@ pub fn parse(config: PathBuf, remote: PathBuf, tmp: String) -> Result<(System, R
         .context(InvalidFile { filename })?;
     let r: TomlRemote = toml::from_str(&content).context(InvalidToml { filename })?;
     debug!("Read {}:\n{:#?}", filename.display(), &r);
-    info!("Looking for updates at '{}'", r.update_url);
+    let mirrors = mirrors(r.update_url, r.dist_url, r.mirror, filename)?;
+    for m in &mirrors {
+        info!("Looking for updates at '{}'", m.update_url);
+    }
+    let limits = limits(r.limits)?;
+    let bandwidth = bandwidth(r.bandwidth)?;
+    if r.retry.attempts == 0 {
+        return Err(Error::InvalidRetry {
+            filename: filename.to_path_buf(),
+        });
+    }
+    let retry = Retry {
+        attempts: r.retry.attempts,
+        delay: Duration::from_secs(r.retry.delay),
+        max_delay: Duration::from_secs(r.retry.max_delay),
+    };
 
-    let filename = &remote.join("rootca.pem");
-    let rootca = Certificate::from_pem(&fs::read(&filename).context(InvalidFile { filename })?)
-        .context(InvalidCertificate { filename })?;
-    debug!("Read {}", filename.display());
+    let proxy = match r.proxy {
+        None => None,
+        Some(p) => Some(proxy(p, &remote)?),
+    };
 
-    let core = Package::new(Kind::Core, &c.core.destination, Some(c.core.size));
-    let efiboot = Package::new(Kind::Efiboot, &c.efiboot.destination, None);
+    let (rootca, identity) = tls(&remote, &mirrors)?;
 
     // Get machine-id from /etc/machine-id
     let filename = "/etc/machine-id";
@@ -194,46 +475,875 @@ pub fn parse(config: PathBuf, remote: PathBuf, tmp: String) -> Result<(System, R
             .context(InvalidHeader { value: machine_id })?,
     );
 
+    let transport = Transport {
+        rootca,
+        identity,
+        headers,
+        connect_timeout: limits.connect_timeout,
+        timeout: limits.read_timeout,
+        proxy,
+        remote,
+    };
+    let client = transport.client()?;
+    // Pins only apply to HTTPS mirrors
+    let pinned = if r.pins.is_empty() || transport.rootca.is_none() {
+        None
+    } else {
+        info!("Pinning server public keys: {}", r.pins.join(", "));
+        Some(pin::Client::new(&r.pins, &transport)?)
+    };
+
+    let system = System::new(c.os_name, packages, version, keys, tmp, state);
+    system.keys().load_revoked(&system.revoked_state())?;
+
     Ok((
-        System::new(c.os_name, core, efiboot, version, pubkey, tmp),
+        system,
         Remote {
-            update_url: r.update_url,
-            dist_url: r.dist_url,
-            rootca,
-            headers,
+            mirrors,
+            limits,
+            retry,
+            pinned,
+            bandwidth,
+            timer: false,
+            dry_run: false,
+            client,
         },
     ))
 }
 
-impl Remote {
-    pub fn check_update(&self, system: &System) -> Result<Option<Version>> {
-        // Setup reqwest Client
-        let client = Client::builder()
-            .add_root_certificate(self.rootca.clone())
+impl Transport {
+    /// Build the reqwest client shared by all requests
+    fn client(&self) -> Result<Client> {
+        let mut builder = Client::builder()
             .default_headers(self.headers.clone())
-            .build()?;
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.timeout);
+        if let Some(pem) = &self.rootca {
+            let filename = &self.remote.join("rootca.pem");
+            builder = builder.add_root_certificate(
+                Certificate::from_pem(pem).context(InvalidCertificate { filename })?,
+            );
+        }
+        if let Some(p) = &self.proxy {
+            builder = builder.proxy(p.proxy());
+        }
+        if let Some(pem) = &self.identity {
+            let filename = &self.remote.join("client.pem");
+            builder =
+                builder.identity(Identity::from_pem(pem).context(InvalidIdentity { filename })?);
+        }
+        builder.build().context(InvalidClient)
+    }
+}
+
+/// Build the download rate limits from `remote.toml`. The timer and
+/// interactive rates default to the common one.
+fn bandwidth(b: TomlBandwidth) -> Result<Bandwidth> {
+    let parse = |name: &str, rate: Option<String>| -> Result<Option<u64>> {
+        match rate {
+            None => Ok(None),
+            Some(r) => Ok(Some(parse_limit(name, &r)?)),
+        }
+    };
+    let rate = parse("rate", b.rate)?;
+    Ok(Bandwidth {
+        timer: parse("timer_rate", b.timer_rate)?.or(rate),
+        interactive: parse("interactive_rate", b.interactive_rate)?.or(rate),
+    })
+}
+
+/// Read the root CA (rootca.pem) and the optional client identity from the
+/// remote configuration folder. Neither is needed if all mirrors are local
+/// directories (`file://` URLs).
+fn tls(remote: &Path, mirrors: &[Mirror]) -> Result<(Option<Vec<u8>>, Option<Vec<u8>>)> {
+    let remote_mirror = mirrors.iter().any(|m| {
+        [&m.update_url, &m.dist_url]
+            .iter()
+            .any(|u| Url::parse(u).map(|u| u.scheme() != "file").unwrap_or(true))
+    });
+    if !remote_mirror {
+        debug!("Only local mirrors, not reading rootca.pem");
+        return Ok((None, None));
+    }
+
+    let filename = &remote.join("rootca.pem");
+    let rootca = fs::read(&filename).context(InvalidFile { filename })?;
+    debug!("Read {}", filename.display());
+    Ok((Some(rootca), identity(remote)?))
+}
+
+/// Read the optional client certificate (client.pem) and private key
+/// (client.key) from the remote configuration folder, as PEM
+fn identity(remote: &Path) -> Result<Option<Vec<u8>>> {
+    let certificate = &remote.join("client.pem");
+    let key = &remote.join("client.key");
+    match (certificate.exists(), key.exists()) {
+        (false, false) => return Ok(None),
+        (true, true) => (),
+        _ => {
+            return Err(Error::IncompleteIdentity {
+                certificate: certificate.clone(),
+                key: key.clone(),
+            })
+        }
+    }
+
+    let mut pem = fs::read(key).context(InvalidFile { filename: key })?;
+    pem.push(b'\n');
+    pem.append(&mut fs::read(certificate).context(InvalidFile {
+        filename: certificate,
+    })?);
+    info!("Using client certificate '{}'", certificate.display());
+    Ok(Some(pem))
+}
+
+/// Read the proxy used for all requests from `remote.toml`. Relative paths
+/// to credentials files are relative to the remote configuration folder.
+fn proxy(p: TomlProxy, remote: &Path) -> Result<ProxySettings> {
+    info!("Using proxy '{}'", p.url);
+    let url = Url::parse(&p.url).context(InvalidProxy {
+        url: p.url.as_str(),
+    })?;
+    let no_proxy = p
+        .no_proxy
+        .iter()
+        .map(|h| h.trim_start_matches('.').to_lowercase())
+        .collect();
+
+    let mut credentials = None;
+    if let Some(c) = p.credentials {
+        let filename = &remote.join(c);
+        let content = fs::read_to_string(filename).context(InvalidFile { filename })?;
+        let mut line = content
+            .lines()
+            .next()
+            .context(InvalidProxyCredentials { filename })?
+            .splitn(2, ':');
+        let username = line.next().unwrap_or("");
+        let password = line.next().context(InvalidProxyCredentials { filename })?;
+        credentials = Some((String::from(username), String::from(password)));
+        debug!("Read proxy credentials from {}", filename.display());
+    }
+
+    Ok(ProxySettings {
+        url,
+        credentials,
+        no_proxy,
+    })
+}
+
+impl ProxySettings {
+    /// Proxy to use to reach a given URL, if any. Hosts are matched including
+    /// their subdomains.
+    pub fn proxy_for(&self, u: &Url) -> Option<&Url> {
+        if self.bypass(u.host_str()?) {
+            None
+        } else {
+            Some(&self.url)
+        }
+    }
+
+    /// Whether a host is reached without the proxy
+    pub fn bypass(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        self.no_proxy
+            .iter()
+            .any(|h| h == "*" || host == *h || host.ends_with(&format!(".{}", h)))
+    }
+
+    /// Value of the Proxy-Authorization header, if any
+    pub fn authorization(&self) -> Option<String> {
+        let (username, password) = self.credentials.as_ref()?;
+        Some(format!(
+            "Basic {}",
+            base64::encode(&format!("{}:{}", username, password))
+        ))
+    }
+
+    /// Proxy configuration for the HTTP client
+    fn proxy(&self) -> Proxy {
+        let settings = self.clone();
+        let mut proxy = Proxy::custom(move |u| settings.proxy_for(u).cloned());
+        if let Some((username, password)) = &self.credentials {
+            proxy = proxy.basic_auth(username, password);
+        }
+        proxy
+    }
+}
+
+/// Build the list of mirrors from `remote.toml`, sorted by priority. The
+/// top-level `update_url` and `dist_url` are kept for compatibility and come
+/// first for a given priority.
+fn mirrors(
+    update_url: Option<String>,
+    dist_url: Option<String>,
+    list: Vec<TomlMirror>,
+    filename: &Path,
+) -> Result<Vec<Mirror>> {
+    let mut mirrors = Vec::new();
+    match (update_url, dist_url) {
+        (Some(update_url), Some(dist_url)) => mirrors.push(Mirror {
+            update_url,
+            dist_url,
+            priority: 0,
+        }),
+        (None, None) => (),
+        _ => {
+            return Err(Error::NoMirror {
+                filename: filename.to_path_buf(),
+            })
+        }
+    }
+    for m in list {
+        mirrors.push(Mirror {
+            update_url: m.update_url,
+            dist_url: m.dist_url,
+            priority: m.priority,
+        });
+    }
+
+    if mirrors.is_empty() {
+        return Err(Error::NoMirror {
+            filename: filename.to_path_buf(),
+        });
+    }
+    mirrors.sort_by_key(|m| m.priority);
+    Ok(mirrors)
+}
 
-        // Get {update_url}/{os_name}/version
-        let url = format!("{}/{}/version", self.update_url, system.os_name);
-        debug!("GET {}", &url);
-        let body = client.get(&url).send()?.text()?;
-        debug!("body = {:?}", body);
+/// Parse a size limit from `remote.toml`
+fn parse_limit(name: &str, size: &str) -> Result<u64> {
+    parse_size(size).context(InvalidLimit { name, size })
+}
+
+/// Build the request limits from `remote.toml`
+fn limits(l: TomlLimits) -> Result<Limits> {
+    Ok(Limits {
+        connect_timeout: Duration::from_secs(l.connect_timeout),
+        read_timeout: Duration::from_secs(l.read_timeout),
+        max_metadata_size: parse_limit("max_metadata_size", &l.max_metadata_size)?,
+        max_payload_size: match l.max_payload_size {
+            None => None,
+            Some(s) => Some(parse_limit("max_payload_size", &s)?),
+        },
+    })
+}
 
-        // Parse response
-        let v: TomlVersion = toml::from_str(&body)?;
-        let version = v.version;
-        debug!("Remote version: {}", version);
+/// Build the list of packages from `config.toml`, sorted by installation
+/// order. The `core` and `efiboot` tables are kept for compatibility and come
+/// first for a given order.
+fn packages(
+    core: Option<TomlCore>,
+    efiboot: Option<TomlEfiboot>,
+    list: Vec<TomlPackage>,
+    filename: &Path,
+) -> Result<Vec<Package>> {
+    let mut packages = Vec::new();
+    if let Some(c) = core {
+        packages.push(Package::new(
+            "core",
+            Kind::Lv,
+            &c.destination,
+            Some(c.size),
+            0,
+            None,
+        ));
+    }
+    if let Some(e) = efiboot {
+        packages.push(Package::new(
+            "efiboot",
+            Kind::Esp,
+            &e.destination,
+            None,
+            0,
+            None,
+        ));
+    }
+    for p in list {
+        let max_size = match &p.max_size {
+            None => None,
+            Some(s) => Some(parse_size(s).context(InvalidSize {
+                name: p.name.as_str(),
+                size: s.as_str(),
+            })?),
+        };
+        packages.push(Package::new(
+            &p.name,
+            p.kind,
+            &p.destination,
+            p.size,
+            p.order,
+            max_size,
+        ));
+    }
+
+    if packages.is_empty() {
+        return Err(Error::NoPackage {
+            filename: filename.to_path_buf(),
+        });
+    }
+    for (i, p) in packages.iter().enumerate() {
+        if packages[..i].iter().any(|o| o.name() == p.name()) {
+            return Err(Error::DuplicatePackage {
+                name: String::from(p.name()),
+            });
+        }
+    }
 
-        // Compare versions
-        let remote_version = Version::parse(&version).context(InvalidVersion { version })?;
+    // ESP binaries are named after the OS and version only, and all other
+    // files in their folder are removed: each package needs its own folder
+    let esps: Vec<&Package> = packages.iter().filter(|p| p.kind() == Kind::Esp).collect();
+    for (i, p) in esps.iter().enumerate() {
+        let dest = Path::new(p.destination());
+        if let Some(o) = esps[..i]
+            .iter()
+            .find(|o| Path::new(o.destination()) == dest)
+        {
+            return Err(Error::DuplicateEsp {
+                first: String::from(o.name()),
+                second: String::from(p.name()),
+                destination: String::from(p.destination()),
+            });
+        }
+    }
+
+    for p in &packages {
+        if let Some(size) = p.size() {
+            if parse_size(size).is_none() {
+                return Err(Error::InvalidSize {
+                    name: String::from(p.name()),
+                    size: String::from(size),
+                });
+            }
+        }
+    }
+
+    // Boot entries must not appear before all LV images are installed
+    packages.sort_by_key(Package::order);
+    for (i, esp) in packages.iter().enumerate() {
+        if esp.kind() != Kind::Esp {
+            continue;
+        }
+        if let Some(lv) = packages[i..].iter().find(|p| p.kind() == Kind::Lv) {
+            return Err(Error::InvalidOrder {
+                esp: String::from(esp.name()),
+                lv: String::from(lv.name()),
+            });
+        }
+    }
+
+    Ok(packages)
+}
+
+impl Remote {
+    /// Ask the remote for the signed manifest of the latest available version
+    /// and return it if this version is newer than the currently running one
+    ///
+    /// Expired manifests and manifests issued before the last accepted one are
+    /// refused with a `MetadataExpired` class error, distinct from the absence
+    /// of an update.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let (system, remote) = updater::parse(
+    /// #     "/usr/lib/updater".into(),
+    /// #     "/etc/updater".into(),
+    /// #     "/var/lib/updater".into(),
+    /// #     "/var/lib/updater-state".into(),
+    /// # )?;
+    /// match remote.check_update(&system)? {
+    ///     None => println!("No update found"),
+    ///     Some(m) => println!("Update available: {}", m.version),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn check_update(&self, system: &System) -> Result<Option<manifest::Manifest>> {
+        let state = &system.manifest_state();
+        let last = manifest::last_issued(state)?;
+        let manifest = self.mirrored(|m| self.manifest(m, system, last))?;
+        if !self.dry_run && last.map_or(true, |l| manifest.issued > l) {
+            manifest::save_issued(state, manifest.issued)?;
+        }
         debug!(
             "local version: '{}' | remote version: '{}'",
-            system.version, remote_version
+            system.version, manifest.version
         );
-        if system.version >= remote_version {
+
+        // Versions below the floor are only offered if a downgrade to them
+        // has been sanctioned, even if they are newer than the running one
+        let floor = system.floor()?;
+        if manifest.version < floor {
+            let fetched = self.mirrored(|m| self.downgrade(m, system))?;
+            let sanctioned = manifest.version != system.version
+                && match fetched {
+                    Some(o) => o.version == manifest.version,
+                    None if self.dry_run => false,
+                    None => system.sanctioned(&manifest.version)?,
+                };
+            if sanctioned {
+                warn!("Sanctioned downgrade to version '{}'", manifest.version);
+                return Ok(Some(manifest));
+            }
+            if manifest.version > system.version {
+                return Err(Error::Floor {
+                    source: floor::Error::BelowFloor {
+                        version: manifest.version,
+                        floor,
+                    },
+                });
+            }
+        }
+        if system.version >= manifest.version {
             return Ok(None);
         }
+        if let Some(v) = system.rolled_back()? {
+            if manifest.version <= v {
+                info!(
+                    "System has been rolled back from version '{}', ignoring version '{}'",
+                    v, manifest.version
+                );
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(manifest))
+    }
+
+    /// Run a request against each mirror by priority until one succeeds.
+    /// Transient errors are retried on the same mirror with an exponential
+    /// backoff. Errors that do not come from the mirror itself are returned
+    /// right away.
+    pub(crate) fn mirrored<T, E, F>(&self, mut f: F) -> std::result::Result<T, E>
+    where
+        E: Classify + From<source::Error>,
+        F: FnMut(&Mirror) -> std::result::Result<T, E>,
+    {
+        let mut last = None;
+        for m in &self.mirrors {
+            let mut delay = self.retry.delay;
+            for attempt in 1..=self.retry.attempts {
+                let e = match f(m) {
+                    Ok(t) => return Ok(t),
+                    Err(e) => e,
+                };
+                match e.class() {
+                    ErrorClass::Network | ErrorClass::Signature | ErrorClass::MetadataExpired => (),
+                    _ => return Err(e),
+                }
+                warn!(
+                    "Request to mirror '{}' failed (attempt {}/{}): {}",
+                    m.dist_url, attempt, self.retry.attempts, e
+                );
+                let retry = e.transient() && attempt < self.retry.attempts;
+                last = Some(e);
+                if !retry {
+                    break;
+                }
+                debug!("Retrying in {} seconds", delay.as_secs());
+                thread::sleep(delay);
+                delay = self.backoff(delay);
+            }
+        }
+        // Mirrors and attempts are checked when parsing the configuration
+        Err(last.unwrap_or_else(|| E::from(source::Error::NoMirror)))
+    }
+
+    /// Delay before the next attempt: twice the previous one, up to the
+    /// maximum delay
+    fn backoff(&self, delay: Duration) -> Duration {
+        let doubled = delay.checked_mul(2).unwrap_or(self.retry.max_delay);
+        std::cmp::min(doubled, self.retry.max_delay)
+    }
+
+    /// Download rate limit for the current run, in bytes per second
+    pub fn rate_limit(&self) -> Option<u64> {
+        if self.timer {
+            self.bandwidth.timer
+        } else {
+            self.bandwidth.interactive
+        }
+    }
+
+    /// Open url for download from the corresponding source: a local directory
+    /// for `file://` URLs, or an HTTPS server. See `Source::open` for resume.
+    pub(crate) fn open(
+        &self,
+        url: &str,
+        resume: Option<&(u64, String)>,
+    ) -> std::result::Result<Response, source::Error> {
+        let url = source::parse_url(url)?;
+        if url.scheme() == "file" {
+            return Local.open(&url, resume);
+        }
+        match &self.pinned {
+            Some(client) => Pinned { client }.open(&url, resume),
+            None => Http {
+                client: &self.client,
+            }
+            .open(&url, resume),
+        }
+    }
+
+    /// Get and verify the manifest of the latest version available on a
+    /// mirror
+    fn manifest(
+        &self,
+        m: &Mirror,
+        system: &System,
+        last_issued: Option<u64>,
+    ) -> Result<manifest::Manifest> {
+        // Revoke keys first so that a manifest signed with a revoked key is
+        // refused
+        self.revoke(m, system)?;
+
+        // Get {update_url}/{os_name}/manifest & its signature
+        let url = format!("{}/{}/manifest", m.update_url, system.os_name);
+        let manifest = self.metadata(&url)?;
+        let signatures = self.signatures(&url, system.keys())?;
+        debug!("manifest = {:?}", String::from_utf8_lossy(&manifest));
+
+        let manifest =
+            manifest::Manifest::verify(&url, &manifest, &signatures, system.keys(), last_issued)?;
+        debug!("Remote version: {}", manifest.version);
+        Ok(manifest)
+    }
+
+    /// Get the revocation list from a mirror, if there is one, and revoke the
+    /// listed keys
+    fn revoke(&self, m: &Mirror, system: &System) -> Result<()> {
+        // Get {update_url}/{os_name}/revoked & its signature
+        let url = format!("{}/{}/revoked", m.update_url, system.os_name);
+        let list = match self.metadata(&url) {
+            Err(Error::Source { ref source }) if source.not_found() => {
+                debug!("No revocation list found");
+                return Ok(());
+            }
+            r => r?,
+        };
+        let signatures = self.signatures(&url, system.keys())?;
+        let state = system.revoked_state();
+        let state = if self.dry_run {
+            None
+        } else {
+            Some(state.as_str())
+        };
+        system.keys().revoke(&url, &list, &signatures, state)?;
+        Ok(())
+    }
+
+    /// Get the downgrade override from a mirror, if there is one, and keep it
+    /// in the state folder to be verified before installation. In dry run
+    /// mode, nothing is kept: the override is verified and returned instead.
+    fn downgrade(&self, m: &Mirror, system: &System) -> Result<Option<floor::Override>> {
+        // Get {update_url}/{os_name}/downgrade & its signature
+        let url = format!("{}/{}/downgrade", m.update_url, system.os_name);
+        let content = match self.metadata(&url) {
+            Err(Error::Source { ref source }) if source.not_found() => {
+                debug!("No downgrade override found");
+                if !self.dry_run {
+                    system.discard_override();
+                }
+                return Ok(None);
+            }
+            r => r?,
+        };
+        let signatures = self.signatures(&url, system.keys())?;
+        if self.dry_run {
+            let o = floor::Override::verify(&url, &content, &signatures, system.keys())?;
+            return Ok(Some(o));
+        }
+        system.keep_override(&url, &content, &signatures)?;
+        Ok(None)
+    }
+
+    /// Get the signatures of a metadata file, see `Keys::fetch_signatures`
+    fn signatures(&self, url: &str, keys: &keys::Keys) -> Result<Vec<(String, Vec<u8>)>> {
+        keys.fetch_signatures(&format!("{}.sig", url), |url| match self.metadata(url) {
+            Err(Error::Source { ref source }) if source.not_found() => {
+                debug!("No signature found at '{}'", url);
+                Ok(None)
+            }
+            r => r.map(Some),
+        })
+    }
 
-        Ok(Some(remote_version))
+    /// Get a metadata file, reading at most the maximum metadata size
+    fn metadata(&self, url: &str) -> Result<Vec<u8>> {
+        debug!("GET {}", url);
+        let res = self.open(url, None)?;
+
+        let limit = self.limits.max_metadata_size;
+        let too_large = || Error::TooLarge {
+            url: String::from(url),
+            limit,
+        };
+        if res.length.unwrap_or(0) > limit {
+            return Err(too_large());
+        }
+        let mut body = Vec::new();
+        res.take(limit + 1)
+            .read_to_end(&mut body)
+            .context(Body { url })?;
+        if body.len() as u64 > limit {
+            return Err(too_large());
+        }
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+impl Remote {
+    /// Remote with the given mirrors, without pins, proxy, retry delay or rate
+    /// limit
+    pub fn test(mirrors: Vec<Mirror>) -> Remote {
+        Remote {
+            mirrors,
+            limits: Limits {
+                connect_timeout: Duration::from_secs(1),
+                read_timeout: Duration::from_secs(1),
+                max_metadata_size: 4096,
+                max_payload_size: None,
+            },
+            retry: Retry {
+                attempts: 1,
+                delay: Duration::from_secs(0),
+                max_delay: Duration::from_secs(0),
+            },
+            pinned: None,
+            bandwidth: Bandwidth {
+                timer: None,
+                interactive: None,
+            },
+            timer: false,
+            dry_run: false,
+            client: Client::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirror(name: &str, priority: u32) -> TomlMirror {
+        TomlMirror {
+            update_url: format!("https://{}/update/v1", name),
+            dist_url: format!("https://{}/dist", name),
+            priority,
+        }
+    }
+
+    /// Remote with the given mirrors, identified by their dist URL
+    fn remote(names: &[&str], attempts: u32) -> Remote {
+        let mirrors = names
+            .iter()
+            .map(|n| Mirror {
+                update_url: String::from(*n),
+                dist_url: String::from(*n),
+                priority: 0,
+            })
+            .collect();
+        let mut r = Remote::test(mirrors);
+        r.retry.attempts = attempts;
+        r
+    }
+
+    /// Run a request failing with the given errors, in order, against the
+    /// mirrors of a remote. Returns the mirrors requested and the result.
+    fn mirrored(
+        r: &Remote,
+        mut results: Vec<source::Result<()>>,
+    ) -> (Vec<String>, source::Result<()>) {
+        let mut requested = Vec::new();
+        results.reverse();
+        let res = r.mirrored(|m| {
+            requested.push(m.dist_url.clone());
+            results.pop().unwrap_or(Ok(()))
+        });
+        (requested, res)
+    }
+
+    /// Transient network error
+    fn timeout() -> source::Result<()> {
+        Err(source::Error::from(pin::Error::Timeout {
+            url: String::from("url"),
+        }))
+    }
+
+    /// Network error not worth retrying on the same mirror
+    fn not_found() -> source::Result<()> {
+        Err(source::Error::from(pin::Error::Status {
+            url: String::from("url"),
+            status: reqwest::StatusCode::NOT_FOUND,
+        }))
+    }
+
+    fn package(name: &str, kind: Kind, destination: &str) -> TomlPackage {
+        TomlPackage {
+            name: String::from(name),
+            kind,
+            destination: String::from(destination),
+            size: None,
+            order: 1,
+            max_size: None,
+        }
+    }
+
+    #[test]
+    fn esp_destinations() {
+        let efiboot = Some(TomlEfiboot {
+            destination: String::from("/mnt/efiboot/EFI/Linux"),
+        });
+        let list = vec![package("recovery", Kind::Esp, "/mnt/efiboot/EFI/Recovery")];
+        let p = packages(None, efiboot, list, Path::new("config.toml")).unwrap();
+        assert_eq!(p.len(), 2);
+
+        let efiboot = Some(TomlEfiboot {
+            destination: String::from("/mnt/efiboot/EFI/Linux"),
+        });
+        let list = vec![package("recovery", Kind::Esp, "/mnt/efiboot/EFI/Linux/")];
+        match packages(None, efiboot, list, Path::new("config.toml")) {
+            Err(Error::DuplicateEsp {
+                ref first,
+                ref second,
+                ..
+            }) => assert_eq!((first.as_str(), second.as_str()), ("efiboot", "recovery")),
+            r => panic!("shared ESP destination accepted: {:?}", r.map(|p| p.len())),
+        }
+
+        // Only ESP packages are concerned
+        let list = vec![
+            package("recovery", Kind::Esp, "/mnt/efiboot/EFI/Linux"),
+            package("config", Kind::File, "/mnt/efiboot/EFI/Linux"),
+        ];
+        assert!(packages(None, None, list, Path::new("config.toml")).is_ok());
+    }
+
+    #[test]
+    fn mirror_order() {
+        let list = vec![mirror("c", 2), mirror("a", 0), mirror("b", 1)];
+        let m = mirrors(
+            Some(String::from("https://main/update/v1")),
+            Some(String::from("https://main/dist")),
+            list,
+            Path::new("remote.toml"),
+        )
+        .unwrap();
+        let order: Vec<&str> = m.iter().map(|m| m.dist_url.as_str()).collect();
+        assert_eq!(
+            order,
+            vec![
+                "https://main/dist",
+                "https://a/dist",
+                "https://b/dist",
+                "https://c/dist"
+            ]
+        );
+    }
+
+    #[test]
+    fn no_mirror() {
+        let none = mirrors(None, None, vec![], Path::new("remote.toml"));
+        let partial = mirrors(
+            Some(String::from("https://main/update/v1")),
+            None,
+            vec![mirror("a", 0)],
+            Path::new("remote.toml"),
+        );
+        for res in vec![none, partial] {
+            match res {
+                Err(Error::NoMirror { .. }) => (),
+                r => panic!("unexpected result: {:?}", r),
+            }
+        }
+
+        let (requested, res) = mirrored(&remote(&[], 1), vec![]);
+        assert!(requested.is_empty());
+        match res {
+            Err(source::Error::NoMirror) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn mirrored_retries() {
+        let r = remote(&["a", "b", "c"], 2);
+
+        // Transient errors are retried on the same mirror first
+        let (requested, res) = mirrored(&r, vec![timeout(), timeout(), timeout()]);
+        assert_eq!(requested, vec!["a", "a", "b", "b"]);
+        assert!(res.is_ok());
+
+        // All mirrors failed: the last error is returned
+        let (requested, res) = mirrored(&r, (0..6).map(|_| timeout()).collect());
+        assert_eq!(requested, vec!["a", "a", "b", "b", "c", "c"]);
+        assert!(res.is_err());
+
+        // Other network errors are not retried on the same mirror
+        let (requested, res) = mirrored(&r, vec![not_found(), not_found()]);
+        assert_eq!(requested, vec!["a", "b", "c"]);
+        assert!(res.is_ok());
+
+        // Errors that do not come from the mirror are returned right away
+        let invalid = Err(source::Error::InvalidPath {
+            url: String::from("url"),
+        });
+        let (requested, res) = mirrored(&r, vec![invalid]);
+        assert_eq!(requested, vec!["a"]);
+        match res {
+            Err(source::Error::InvalidPath { .. }) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn local_mirrors_without_rootca() {
+        let dir = PathBuf::from(crate::state::test_dir("config-local-mirrors"));
+        let local = |name: &str| Mirror {
+            update_url: format!("file:///srv/{}/update/v1", name),
+            dist_url: format!("file:///srv/{}/dist", name),
+            priority: 0,
+        };
+        let mut mirrors = vec![local("a"), local("b")];
+        match tls(&dir, &mirrors) {
+            Ok((None, None)) => (),
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+
+        // The client certificate is ignored as well
+        fs::write(dir.join("client.pem"), "").unwrap();
+        assert!(tls(&dir, &mirrors).is_ok());
+
+        mirrors.push(Mirror {
+            update_url: String::from("https://main/update/v1"),
+            dist_url: String::from("https://main/dist"),
+            priority: 1,
+        });
+        match tls(&dir, &mirrors) {
+            Err(Error::InvalidFile { ref filename, .. }) => {
+                assert_eq!(filename, &dir.join("rootca.pem"))
+            }
+            r => panic!("missing root CA accepted: {:?}", r.map(|_| ())),
+        }
+        fs::write(dir.join("rootca.pem"), "").unwrap();
+        match tls(&dir, &mirrors) {
+            Err(Error::IncompleteIdentity { .. }) => (),
+            r => panic!("incomplete identity accepted: {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn backoff() {
+        let mut r = remote(&["a"], 1);
+        r.retry.max_delay = Duration::from_secs(60);
+        assert_eq!(r.backoff(Duration::from_secs(1)), Duration::from_secs(2));
+        assert_eq!(r.backoff(Duration::from_secs(40)), Duration::from_secs(60));
+        assert_eq!(
+            r.backoff(Duration::from_secs(u64::max_value())),
+            Duration::from_secs(60)
+        );
     }
 }
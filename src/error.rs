@@ -0,0 +1,89 @@
+// SPDX-License-Identifier: LGPL-2.1-or-later
+// Copyright © 2019 ANSSI. All rights reserved.
+
+//! Error classes and their corresponding process exit codes.
+//!
+//! | Code | Class              | Description                                   |
+//! |------|--------------------|-----------------------------------------------|
+//! | 0    |                    | Success, or no update available               |
+//! | 1    | `other`            | Unclassified error                            |
+//! | 10   | `config`           | Invalid or missing local configuration        |
+//! | 11   | `network`          | HTTP request failed or invalid remote answer  |
+//! | 12   | `signature`        | Invalid or undecodable payload signature      |
+//! | 13   | `version_mismatch` | Unexpected version for an update payload      |
+//! | 14   | `lvm`              | LVM command failed or unexpected LVM state    |
+//! | 15   | `disk_space`       | Not enough free space to proceed              |
+//! | 16   | `io`               | Local file or device operation failed         |
+//! | 17   | `metadata_expired` | Expired or outdated update metadata           |
+//! | 18   | `downgrade`        | Version below the anti-rollback floor         |
+
+use std::fmt;
+
+/// Class of an error, used to select the process exit code
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    Other,
+    Config,
+    Network,
+    Signature,
+    VersionMismatch,
+    Lvm,
+    DiskSpace,
+    Io,
+    MetadataExpired,
+    Downgrade,
+}
+
+impl ErrorClass {
+    /// Process exit code for this class of errors
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorClass::Other => 1,
+            ErrorClass::Config => 10,
+            ErrorClass::Network => 11,
+            ErrorClass::Signature => 12,
+            ErrorClass::VersionMismatch => 13,
+            ErrorClass::Lvm => 14,
+            ErrorClass::DiskSpace => 15,
+            ErrorClass::Io => 16,
+            ErrorClass::MetadataExpired => 17,
+            ErrorClass::Downgrade => 18,
+        }
+    }
+}
+
+/// Errors that can be sorted into an error class
+pub trait Classify: fmt::Display {
+    fn class(&self) -> ErrorClass;
+
+    /// Whether the failed operation may succeed if retried as is
+    fn transient(&self) -> bool {
+        false
+    }
+}
+
+/// Machine readable error report
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub class: ErrorClass,
+    pub code: i32,
+    pub message: String,
+}
+
+impl Report {
+    pub fn new<E: Classify>(e: &E) -> Report {
+        let class = e.class();
+        Report {
+            class,
+            code: class.exit_code(),
+            message: e.to_string(),
+        }
+    }
+}
+
+impl Classify for serde_json::Error {
+    fn class(&self) -> ErrorClass {
+        ErrorClass::Other
+    }
+}
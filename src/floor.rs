@@ -0,0 +1,260 @@
+// SPDX-License-Identifier: LGPL-2.1-or-later
+// Copyright © 2019 ANSSI. All rights reserved.
+
+//! Anti-rollback floor.
+//!
+//! The highest version ever installed is recorded in a state file, and
+//! versions below it are refused even if they are newer than the running
+//! version: booting an older version, e.g. after a rollback, does not allow
+//! installing a version that has already been replaced.
+//!
+//! A downgrade below the floor must be sanctioned by an override served next
+//! to the manifest as `<update_url>/<os_name>/downgrade` and `downgrade.sig`,
+//! and signed with the trusted keys, like the manifest:
+//!
+//! ```toml
+//! version = "5.0.0-alpha.2"  # the only version allowed below the floor
+//! expires = 1577836800       # seconds since the Unix epoch
+//! ```
+//!
+//! Once the sanctioned version has been installed, it becomes the new floor
+//! and the override is discarded. As the manifest for this version can not be
+//! replayed once a newer one has been accepted, neither can the override.
+
+use semver::Version;
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::error::{Classify, ErrorClass};
+use crate::keys;
+use crate::keys::Keys;
+use crate::manifest;
+use crate::state;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Invalid downgrade override signature: {}", source))]
+    Signature { source: keys::Error },
+    #[snafu(display("Could not parse downgrade override: {}", source))]
+    InvalidOverride { source: toml::de::Error },
+    #[snafu(display("Could not parse '{}' as valid version: {}", version, source))]
+    InvalidVersion {
+        version: String,
+        source: semver::SemVerError,
+    },
+    #[snafu(display(
+        "Downgrade override expired at {} (seconds since the Unix epoch)",
+        expires
+    ))]
+    Expired { expires: u64 },
+    #[snafu(display(
+        "Version '{}' is below the anti-rollback floor '{}' and no downgrade to it has been sanctioned",
+        version,
+        floor
+    ))]
+    BelowFloor { version: Version, floor: Version },
+    #[snafu(display("Could not read or write anti-rollback state '{}': {}", filename.display(), source))]
+    State {
+        filename: PathBuf,
+        source: io::Error,
+    },
+    #[snafu(display("Invalid version in state file '{}'", filename.display()))]
+    InvalidState { filename: PathBuf },
+}
+
+impl Classify for Error {
+    fn class(&self) -> ErrorClass {
+        match self {
+            Error::BelowFloor { .. } => ErrorClass::Downgrade,
+            Error::Expired { .. } => ErrorClass::MetadataExpired,
+            Error::State { .. } | Error::InvalidState { .. } => ErrorClass::Io,
+            Error::Signature { source } => source.class(),
+            _ => ErrorClass::Signature,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Used to parse downgrade overrides
+#[derive(Deserialize, Debug)]
+struct TomlOverride {
+    version: String,
+    expires: u64,
+}
+
+/// Verified override sanctioning a downgrade below the floor
+#[derive(Debug)]
+pub struct Override {
+    pub version: Version,
+    /// Expiry time in seconds since the Unix epoch
+    pub expires: u64,
+}
+
+impl Override {
+    /// Verify the override signatures with the trusted keys, then parse it
+    /// and make sure that it has not expired
+    pub fn verify(
+        name: &str,
+        content: &[u8],
+        signatures: &[(String, Vec<u8>)],
+        keys: &Keys,
+    ) -> Result<Override> {
+        keys.verify(name, content, signatures).context(Signature)?;
+
+        let o: TomlOverride =
+            toml::from_str(&String::from_utf8_lossy(content)).context(InvalidOverride)?;
+        let version = Version::parse(&o.version).context(InvalidVersion {
+            version: o.version.as_str(),
+        })?;
+
+        if manifest::expired(o.expires) {
+            return Err(Error::Expired { expires: o.expires });
+        }
+        Ok(Override {
+            version,
+            expires: o.expires,
+        })
+    }
+
+    /// Read an override kept in a local file along with its signature files,
+    /// if there is one, and verify it
+    pub fn load(filename: &str, sigs: Vec<String>, keys: &Keys) -> Result<Option<Override>> {
+        let content = match fs::read(filename) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            r => r.context(State { filename })?,
+        };
+        let mut signatures = Vec::new();
+        for sig in sigs {
+            let s = fs::read(&sig).context(State { filename: &sig })?;
+            signatures.push((sig, s));
+        }
+        Override::verify(filename, &content, &signatures, keys).map(Some)
+    }
+}
+
+/// Keep an override and its signatures, as fetched from url, in a local file.
+/// Signature files keep the `.sig` or `.sig.<n>` suffix from their URL.
+pub fn keep(
+    filename: &str,
+    url: &str,
+    content: &[u8],
+    signatures: &[(String, Vec<u8>)],
+) -> Result<()> {
+    for (sig, s) in signatures {
+        let dst = &format!("{}{}", filename, sig.get(url.len()..).unwrap_or(".sig"));
+        state::write(dst, s).context(State { filename: dst })?;
+    }
+    state::write(filename, content).context(State { filename })
+}
+
+/// Read the version recorded in a state file, if any: the highest version ever
+/// installed, or the version the system has been rolled back from
+pub fn load(filename: &str) -> Result<Option<Version>> {
+    let state = match fs::read_to_string(filename) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        r => r.context(State { filename })?,
+    };
+    let version = Version::parse(state.trim())
+        .ok()
+        .context(InvalidState { filename })?;
+    Ok(Some(version))
+}
+
+/// Record a version in a state file
+pub fn save(filename: &str, version: &Version) -> Result<()> {
+    state::write(filename, format!("{}\n", version).as_bytes()).context(State { filename })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_dir;
+    use std::path::Path;
+
+    const DOWNGRADE: &[u8] = include_bytes!("../test/metadata/downgrade");
+    const SIG: &[u8] = include_bytes!("../test/metadata/downgrade.sig");
+
+    fn keys() -> Keys {
+        let config = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/client/config");
+        Keys::load(&config, 1).unwrap()
+    }
+
+    fn verify(content: &[u8], sig: &[u8]) -> Result<Override> {
+        let signatures = [(String::from("downgrade.sig"), sig.to_vec())];
+        Override::verify("downgrade", content, &signatures, &keys())
+    }
+
+    #[test]
+    fn valid_override() {
+        let o = verify(DOWNGRADE, SIG).unwrap();
+        assert_eq!(o.version, Version::parse("5.0.0-alpha.2").unwrap());
+        assert_eq!(o.expires, 4_102_444_800);
+    }
+
+    #[test]
+    fn tampered_override() {
+        let tampered = String::from_utf8_lossy(DOWNGRADE).replace("alpha.2", "alpha.0");
+        match verify(tampered.as_bytes(), SIG) {
+            Err(Error::Signature { .. }) => (),
+            r => panic!("tampered override accepted: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn expired_override() {
+        let content = include_bytes!("../test/metadata/downgrade.expired");
+        let sig = include_bytes!("../test/metadata/downgrade.expired.sig");
+        match verify(content, sig) {
+            Err(Error::Expired { expires }) => assert_eq!(expires, 1_561_939_200),
+            r => panic!("expired override accepted: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn kept_override() {
+        let dir = test_dir("floor-override");
+        let file = &format!("{}/downgrade", dir);
+        let sig = format!("{}.sig", file);
+        assert!(Override::load(file, vec![], &keys()).unwrap().is_none());
+
+        let url = "https://update.clip-os.org/update/v1/clipos/downgrade";
+        let signatures = [(format!("{}.sig", url), SIG.to_vec())];
+        keep(file, url, DOWNGRADE, &signatures).unwrap();
+        assert_eq!(fs::read(&sig).unwrap(), SIG);
+        let o = Override::load(file, vec![sig.clone()], &keys()).unwrap();
+        assert_eq!(
+            o.map(|o| o.version.to_string()),
+            Some(String::from("5.0.0-alpha.2"))
+        );
+
+        // Without its signature, the override is refused
+        match Override::load(file, vec![], &keys()) {
+            Err(Error::Signature { .. }) => (),
+            r => panic!("unsigned override accepted: {:?}", r),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn floor_state() {
+        let dir = test_dir("floor-state");
+        let state = &format!("{}/floor", dir);
+        assert_eq!(load(state).unwrap(), None);
+
+        let version = Version::parse("5.0.0-alpha.3").unwrap();
+        save(state, &version).unwrap();
+        assert_eq!(load(state).unwrap(), Some(version));
+        save(state, &Version::parse("5.0.0").unwrap()).unwrap();
+        assert_eq!(load(state).unwrap(), Version::parse("5.0.0").ok());
+
+        fs::write(state, "not a version\n").unwrap();
+        match load(state) {
+            Err(Error::InvalidState { .. }) => (),
+            r => panic!("invalid state accepted: {:?}", r),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
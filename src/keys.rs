@@ -0,0 +1,779 @@
+// SPDX-License-Identifier: LGPL-2.1-or-later
+// Copyright © 2019 ANSSI. All rights reserved.
+
+//! Trusted signing keys.
+//!
+//! Update metadata and payloads may be signed with any of the trusted minisign
+//! public keys: the legacy `pubkey` file and all the `*.pub` files in the
+//! `keys` folder of the system configuration. The key used to verify a
+//! signature is selected by the key ID in this signature, so that releases can
+//! move to a new key once it has been shipped to clients.
+//!
+//! Release payloads and signed metadata (manifest, revocation list and
+//! downgrade override) may have to be signed with several distinct trusted
+//! keys, as set by the `threshold` option of `config.toml`. Additional
+//! signatures are stored next to the first one as `.sig.1`, `.sig.2`, etc.
+//!
+//! Keys are revoked by a revocation list served next to the manifest as
+//! `<update_url>/<os_name>/revoked` and `revoked.sig`, signed by the trusted
+//! keys:
+//!
+//! ```toml
+//! revoked = ["6C5A3B8F0D1E2A47"]  # key IDs as displayed by minisign
+//! ```
+//!
+//! Revoked key IDs are kept in a state file so that serving an older list
+//! does not bring a revoked key back.
+
+use blake2::{Blake2b, Digest};
+use minisign::{PublicKey, SignatureBox};
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::fs;
+use std::io;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use untrusted::Input;
+
+use crate::error::{Classify, ErrorClass};
+use crate::state;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not read keys folder '{}': {}", path.display(), source))]
+    ReadDir { path: PathBuf, source: io::Error },
+    #[snafu(display("Could not parse public key from '{}': {}", filename.display(), source))]
+    InvalidPublicKey {
+        filename: PathBuf,
+        source: minisign::PError,
+    },
+    #[snafu(display("Could not read key ID from '{}'", filename.display()))]
+    InvalidKeyFile { filename: PathBuf },
+    #[snafu(display("No trusted public key found in '{}'", path.display()))]
+    NoKey { path: PathBuf },
+    #[snafu(display(
+        "Invalid signature threshold {}: must be between 1 and the number of trusted keys ({})",
+        threshold,
+        keys
+    ))]
+    InvalidThreshold { threshold: usize, keys: usize },
+    #[snafu(display("Could not read key ID from signature"))]
+    MissingKeyId,
+    #[snafu(display("Signature made with unknown key '{}'", id))]
+    UnknownKey { id: String },
+    #[snafu(display("Signature made with revoked key '{}'", id))]
+    RevokedKey { id: String },
+    #[snafu(display("Invalid signature '{}': {}", filename, source))]
+    InvalidSignature {
+        filename: String,
+        source: minisign::PError,
+    },
+    #[snafu(display(
+        "'{}' is signed by {} distinct trusted keys, {} required",
+        name,
+        valid,
+        threshold
+    ))]
+    NotEnoughSignatures {
+        name: String,
+        valid: usize,
+        threshold: usize,
+    },
+    #[snafu(display("Could not parse revocation list: {}", source))]
+    InvalidRevocationList { source: toml::de::Error },
+    #[snafu(display("Invalid key ID '{}' in revocation list", id))]
+    InvalidKeyId { id: String },
+    #[snafu(display("Could not read or write revocation state '{}': {}", filename.display(), source))]
+    State {
+        filename: PathBuf,
+        source: io::Error,
+    },
+    #[snafu(display("Invalid revocation state in '{}'", filename.display()))]
+    InvalidState { filename: PathBuf },
+}
+
+impl Classify for Error {
+    fn class(&self) -> ErrorClass {
+        match self {
+            Error::ReadDir { .. }
+            | Error::InvalidPublicKey { .. }
+            | Error::InvalidKeyFile { .. }
+            | Error::NoKey { .. }
+            | Error::InvalidThreshold { .. } => ErrorClass::Config,
+            Error::State { .. } | Error::InvalidState { .. } => ErrorClass::Io,
+            _ => ErrorClass::Signature,
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Used to parse revocation lists
+#[derive(Deserialize, Debug)]
+struct TomlRevocationList {
+    revoked: Vec<String>,
+}
+
+/// Trusted minisign public key
+pub struct TrustedKey {
+    id: u64,
+    /// Used to verify legacy signatures with minisign
+    pubkey: PublicKey,
+    /// Raw Ed25519 public key, used to verify prehashed signatures
+    ed25519: Vec<u8>,
+}
+
+/// Trusted public keys, indexed by key ID, revoked key IDs and the number of
+/// distinct keys required to sign release payloads
+pub struct Keys {
+    keys: Vec<TrustedKey>,
+    revoked: Mutex<Vec<u64>>,
+    threshold: usize,
+}
+
+impl Keys {
+    /// Load the `pubkey` file and the `*.pub` files in the `keys` folder from
+    /// the configuration folder
+    pub fn load(config: &Path, threshold: usize) -> Result<Keys> {
+        let mut files = Vec::new();
+        let legacy = config.join("pubkey");
+        if legacy.exists() {
+            files.push(legacy);
+        }
+        let path = config.join("keys");
+        if path.is_dir() {
+            let mut entries = Vec::new();
+            for entry in fs::read_dir(&path).context(ReadDir { path: &path })? {
+                let file = entry.context(ReadDir { path: &path })?.path();
+                if file.extension().map_or(false, |e| e == "pub") {
+                    entries.push(file);
+                }
+            }
+            entries.sort();
+            files.append(&mut entries);
+        }
+
+        let mut keys = Vec::new();
+        for filename in files {
+            let pubkey = PublicKey::from_file(&filename).context(InvalidPublicKey {
+                filename: &filename,
+            })?;
+            let (id, ed25519) = fs::read_to_string(&filename)
+                .ok()
+                .and_then(|k| Some((key_id(&k)?, ed25519_key(&k)?)))
+                .context(InvalidKeyFile {
+                    filename: &filename,
+                })?;
+            // The same key may be both in `pubkey` and in the `keys` folder,
+            // it must only count once towards the threshold
+            if keys.iter().any(|k: &TrustedKey| k.id == id) {
+                debug!(
+                    "Ignoring public key '{}' from {}: already trusted",
+                    format_id(id),
+                    filename.display()
+                );
+                continue;
+            }
+            debug!(
+                "Read public key '{}' from {}",
+                format_id(id),
+                filename.display()
+            );
+            keys.push(TrustedKey {
+                id,
+                pubkey,
+                ed25519,
+            });
+        }
+        if keys.is_empty() {
+            return Err(Error::NoKey {
+                path: config.to_path_buf(),
+            });
+        }
+        if threshold == 0 || threshold > keys.len() {
+            return Err(Error::InvalidThreshold {
+                threshold,
+                keys: keys.len(),
+            });
+        }
+
+        Ok(Keys {
+            keys,
+            revoked: Mutex::new(Vec::new()),
+            threshold,
+        })
+    }
+
+    /// Number of trusted keys
+    pub fn count(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Number of distinct trusted keys required to sign release payloads and
+    /// metadata
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    /// Select the trusted key to verify the given minisign signature with
+    pub fn get(&self, signature: &str) -> Result<&TrustedKey> {
+        let id = key_id(signature).context(MissingKeyId)?;
+        if self.is_revoked(id) {
+            return Err(Error::RevokedKey { id: format_id(id) });
+        }
+        self.keys
+            .iter()
+            .find(|k| k.id == id)
+            .context(UnknownKey { id: format_id(id) })
+    }
+
+    /// Number of distinct trusted keys the given signatures claim to be made
+    /// with, to know whether more signatures are needed to reach the threshold
+    pub fn signers(&self, signatures: &[(String, Vec<u8>)]) -> usize {
+        let mut ids = Vec::new();
+        for (_, content) in signatures {
+            let content = String::from_utf8_lossy(content);
+            if self.get(&content).is_ok() {
+                let id = key_id(&content);
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.len()
+    }
+
+    /// Get the signatures of a file with fetch: `<sig>`, then `<sig>.1`,
+    /// `<sig>.2`, etc. only if the threshold is above 1, and only until enough
+    /// distinct trusted keys are found. Only some of them may be available:
+    /// fetch returns `None` for missing signatures, which are skipped.
+    pub fn fetch_signatures<F, E>(
+        &self,
+        sig: &str,
+        mut fetch: F,
+    ) -> std::result::Result<Vec<(String, Vec<u8>)>, E>
+    where
+        F: FnMut(&str) -> std::result::Result<Option<Vec<u8>>, E>,
+    {
+        let mut signatures = Vec::new();
+        for n in 0..=self.count() {
+            if n > 0 && (self.threshold == 1 || self.signers(&signatures) >= self.threshold) {
+                break;
+            }
+            let name = match n {
+                0 => String::from(sig),
+                n => format!("{}.{}", sig, n),
+            };
+            if let Some(content) = fetch(&name)? {
+                signatures.push((name, content));
+            }
+        }
+        Ok(signatures)
+    }
+
+    /// Verify that data is signed with at least `threshold` distinct trusted
+    /// keys. Signatures are given with their filename or URL. Signatures that
+    /// can not be decoded, made with unknown or revoked keys, or with a key
+    /// already used, are ignored.
+    pub fn verify(&self, name: &str, data: &[u8], signatures: &[(String, Vec<u8>)]) -> Result<()> {
+        let mut ids = Vec::new();
+        for (filename, content) in signatures {
+            let content = String::from_utf8_lossy(content);
+            let key = match self.get(&content) {
+                Err(e) => {
+                    warn!("Ignoring signature '{}': {}", filename, e);
+                    continue;
+                }
+                Ok(k) => k,
+            };
+            let id = key_id(&content);
+            if ids.contains(&id) {
+                warn!("Ignoring signature '{}': key already used", filename);
+                continue;
+            }
+            let sig = match SignatureBox::from_string(&content) {
+                Err(e) => {
+                    warn!("Ignoring signature '{}': {}", filename, e);
+                    continue;
+                }
+                Ok(s) => s,
+            };
+            let verified = if sig.is_prehashed() {
+                verify_prehashed(key, &sig, &Blake2b::digest(data))
+            } else {
+                minisign::verify(&key.pubkey, &sig, Cursor::new(data), true, false)
+            };
+            verified.context(InvalidSignature {
+                filename: filename.as_str(),
+            })?;
+            ids.push(id);
+        }
+
+        if ids.len() < self.threshold {
+            return Err(Error::NotEnoughSignatures {
+                name: String::from(name),
+                valid: ids.len(),
+                threshold: self.threshold,
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether the key with the given ID has been revoked
+    fn is_revoked(&self, id: u64) -> bool {
+        self.revoked.lock().map(|r| r.contains(&id)).unwrap_or(true)
+    }
+
+    /// Load revoked key IDs from the state file, if any
+    pub fn load_revoked(&self, state: &str) -> Result<()> {
+        let content = match fs::read_to_string(state) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            r => r.context(State { filename: state })?,
+        };
+        let mut ids = Vec::new();
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            ids.push(parse_id(line).context(InvalidState { filename: state })?);
+        }
+        if let Ok(mut r) = self.revoked.lock() {
+            r.append(&mut ids);
+        }
+        Ok(())
+    }
+
+    /// Verify a revocation list with its signatures, then revoke the listed
+    /// keys and record them in the state file, if one is given
+    pub fn revoke(
+        &self,
+        url: &str,
+        list: &[u8],
+        signatures: &[(String, Vec<u8>)],
+        state: Option<&str>,
+    ) -> Result<()> {
+        self.verify(url, list, signatures)?;
+
+        let l: TomlRevocationList =
+            toml::from_str(&String::from_utf8_lossy(list)).context(InvalidRevocationList)?;
+        let mut ids = Vec::new();
+        for id in l.revoked {
+            ids.push(parse_id(&id).context(InvalidKeyId { id: id.as_str() })?);
+        }
+
+        let revoked = match self.revoked.lock() {
+            Err(_) => return Ok(()),
+            Ok(mut r) => {
+                let new: Vec<u64> = ids.into_iter().filter(|id| !r.contains(id)).collect();
+                if new.is_empty() {
+                    return Ok(());
+                }
+                for id in new {
+                    warn!("Revoking key '{}'", format_id(id));
+                    r.push(id);
+                }
+                r.clone()
+            }
+        };
+
+        let state = match state {
+            None => return Ok(()),
+            Some(s) => s,
+        };
+        let content: String = revoked
+            .iter()
+            .map(|id| format!("{}\n", format_id(*id)))
+            .collect();
+        state::write(state, content.as_bytes()).context(State { filename: state })
+    }
+}
+
+/// Read the key ID from the base64 encoded line of a minisign public key or
+/// signature
+pub fn key_id(text: &str) -> Option<u64> {
+    let encoded = text.lines().nth(1)?;
+    let bytes = base64::decode(encoded.trim()).ok()?;
+    if bytes.len() < 10 {
+        return None;
+    }
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&bytes[2..10]);
+    Some(u64::from_le_bytes(id))
+}
+
+/// Read the raw Ed25519 public key from the base64 encoded line of a minisign
+/// public key
+fn ed25519_key(text: &str) -> Option<Vec<u8>> {
+    let encoded = text.lines().nth(1)?;
+    let bytes = base64::decode(encoded.trim()).ok()?;
+    if bytes.len() != 42 || !bytes.starts_with(b"Ed") {
+        return None;
+    }
+    Some(bytes[10..].to_vec())
+}
+
+/// Format a key ID as displayed by minisign
+pub fn format_id(id: u64) -> String {
+    format!("{:X}", id)
+}
+
+/// Parse a key ID as displayed by minisign
+fn parse_id(id: &str) -> Option<u64> {
+    u64::from_str_radix(id.trim(), 16).ok()
+}
+
+/// Verify a prehashed signature (`minisign -H`) given the BLAKE2b-512 hash of
+/// the signed data, so that large files can be hashed while they are
+/// downloaded.
+///
+/// `minisign::verify` can not be used for these: it only takes a reader it
+/// consumes itself, so payloads would have to be read again once written,
+/// and minisign 0.5 computes the BLAKE2b hash over its whole read buffer
+/// rather than over the bytes each read returned, so the hash is wrong as
+/// soon as a read is short, as the last one of most files. This is done here
+/// instead, following the minisign signature format: the signature line holds
+/// the `ED` algorithm, the key ID and the Ed25519 signature of the hash. The
+/// global signature covers the latter followed by the trusted comment, and is
+/// verified too as the trusted comment holds the version.
+pub fn verify_prehashed(
+    key: &TrustedKey,
+    signature: &SignatureBox,
+    prehash: &[u8],
+) -> std::result::Result<(), minisign::PError> {
+    let invalid = |msg: &'static str| minisign::PError::new(minisign::ErrorKind::Verify, msg);
+    let ed25519 = |msg: &[u8], sig: &[u8]| {
+        ring::signature::verify(
+            &ring::signature::ED25519,
+            Input::from(&key.ed25519[..]),
+            Input::from(msg),
+            Input::from(sig),
+        )
+        .map_err(|_| invalid("Signature verification failed"))
+    };
+
+    // Untrusted comment, signature, trusted comment and global signature
+    let text = signature.clone().into_string();
+    let mut lines = text.lines();
+    let sig = lines
+        .nth(1)
+        .and_then(|l| base64::decode(l.trim()).ok())
+        .filter(|b| b.len() == 74)
+        .ok_or_else(|| invalid("Could not decode signature"))?;
+    if !sig.starts_with(b"ED") {
+        return Err(invalid("Signature is not prehashed"));
+    }
+    if sig[2..10] != key.id.to_le_bytes() {
+        return Err(invalid("Signature made with another key"));
+    }
+    ed25519(prehash, &sig[10..])?;
+
+    let global = lines
+        .nth(1)
+        .and_then(|l| base64::decode(l.trim()).ok())
+        .ok_or_else(|| invalid("Could not decode global signature"))?;
+    let mut signed = sig[10..].to_vec();
+    signed.extend(signature.trusted_comment()?.as_bytes());
+    ed25519(&signed, &global)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_dir;
+
+    const MANIFEST: &[u8] = include_bytes!("../webroot/update/v1/clipos/manifest");
+    /// Signatures of the manifest made with the test key, then with the
+    /// second test key
+    const SIG: &[u8] = include_bytes!("../webroot/update/v1/clipos/manifest.sig");
+    const SIG_SECOND: &[u8] = include_bytes!("../test/metadata/manifest.sig.1");
+
+    const TEST_KEY: u64 = 0x70D8_30FF_14CD_FCC9;
+    const SECOND_KEY: u64 = 0xE482_49A8_8D15_986E;
+
+    /// Load the test key and the second test key from a `keys` folder
+    fn keys(threshold: usize) -> Result<Keys> {
+        Keys::load(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("test/keys/trusted"),
+            threshold,
+        )
+    }
+
+    fn sigs(sigs: &[&[u8]]) -> Vec<(String, Vec<u8>)> {
+        sigs.iter()
+            .enumerate()
+            .map(|(n, s)| (format!("manifest.sig.{}", n), s.to_vec()))
+            .collect()
+    }
+
+    fn verify(keys: &Keys, signatures: &[&[u8]]) -> Result<()> {
+        keys.verify("manifest", MANIFEST, &sigs(signatures))
+    }
+
+    fn not_enough(res: Result<()>, expected: usize) {
+        match res {
+            Err(Error::NotEnoughSignatures { valid, .. }) => assert_eq!(valid, expected),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn key_ids() {
+        let pubkey = include_str!("../test/keys/pub");
+        assert_eq!(key_id(pubkey), Some(TEST_KEY));
+        assert_eq!(key_id(&String::from_utf8_lossy(SIG)), Some(TEST_KEY));
+        assert_eq!(
+            key_id(&String::from_utf8_lossy(SIG_SECOND)),
+            Some(SECOND_KEY)
+        );
+        assert_eq!(format_id(TEST_KEY), "70D830FF14CDFCC9");
+        assert_eq!(parse_id("70D830FF14CDFCC9\n"), Some(TEST_KEY));
+
+        assert_eq!(key_id(""), None);
+        assert_eq!(key_id("untrusted comment\nnot base64"), None);
+        assert_eq!(key_id("untrusted comment\nRWQ="), None);
+        assert_eq!(parse_id("not an ID"), None);
+    }
+
+    #[test]
+    fn load_keys_folder() {
+        let keys = keys(1).unwrap();
+        assert_eq!(keys.count(), 2);
+        assert_eq!(keys.threshold(), 1);
+        assert!(keys.get(&String::from_utf8_lossy(SIG)).is_ok());
+        assert!(keys.get(&String::from_utf8_lossy(SIG_SECOND)).is_ok());
+
+        let config = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/client/config");
+        let legacy = Keys::load(&config, 1).unwrap();
+        assert_eq!(legacy.count(), 1);
+        match legacy.get(&String::from_utf8_lossy(SIG_SECOND)) {
+            Err(Error::UnknownKey { id }) => assert_eq!(id, "E48249A88D15986E"),
+            _ => panic!("signature from unknown key accepted"),
+        }
+    }
+
+    #[test]
+    fn load_without_keys() {
+        let config = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/server");
+        match Keys::load(&config, 1) {
+            Err(Error::NoKey { .. }) => (),
+            _ => panic!("no key found but no error"),
+        }
+    }
+
+    #[test]
+    fn load_duplicate_keys() {
+        // The test key is both in `pubkey` and in the `keys` folder
+        let config = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/keys/duplicate");
+        let keys = Keys::load(&config, 1).unwrap();
+        assert_eq!(keys.count(), 1);
+        match Keys::load(&config, 2) {
+            Err(Error::InvalidThreshold { keys, .. }) => assert_eq!(keys, 1),
+            _ => panic!("threshold above the number of distinct keys accepted"),
+        }
+    }
+
+    #[test]
+    fn invalid_threshold() {
+        for threshold in &[0, 3] {
+            match keys(*threshold) {
+                Err(Error::InvalidThreshold { keys, .. }) => assert_eq!(keys, 2),
+                _ => panic!("threshold {} accepted", threshold),
+            }
+        }
+    }
+
+    #[test]
+    fn verify_single_signature() {
+        let keys = keys(1).unwrap();
+        assert!(verify(&keys, &[SIG]).is_ok());
+        assert!(verify(&keys, &[SIG_SECOND]).is_ok());
+        not_enough(verify(&keys, &[]), 0);
+
+        let tampered = keys.verify("manifest", b"tampered", &sigs(&[SIG]));
+        match tampered {
+            Err(Error::InvalidSignature { .. }) => (),
+            r => panic!("tampered data accepted: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn prehashed_signatures() {
+        let keys = keys(1).unwrap();
+        let text = String::from_utf8_lossy(SIG);
+        let key = keys.get(&text).unwrap();
+        let sig = SignatureBox::from_string(&text).unwrap();
+        let prehash = Blake2b::digest(MANIFEST);
+        assert!(verify_prehashed(key, &sig, &prehash).is_ok());
+        assert!(verify_prehashed(key, &sig, &Blake2b::digest(b"tampered")).is_err());
+
+        // Signature made with another key than the given one
+        let second = keys.get(&String::from_utf8_lossy(SIG_SECOND)).unwrap();
+        assert!(verify_prehashed(second, &sig, &prehash).is_err());
+
+        // Legacy signatures are only accepted for metadata
+        let legacy: &[u8] = include_bytes!("../test/metadata/manifest.legacy.sig");
+        let sig = SignatureBox::from_string(&String::from_utf8_lossy(legacy)).unwrap();
+        assert!(!sig.is_prehashed());
+        assert!(verify_prehashed(key, &sig, &prehash).is_err());
+        assert!(verify(&keys, &[legacy]).is_ok());
+    }
+
+    #[test]
+    fn invalid_prehashed_signatures() {
+        // Signatures of the manifest made with the test key in the minisign
+        // format, then altered
+        let vectors: &[(&str, &[u8])] = &[
+            // Prehashed signature with the legacy algorithm tag
+            (
+                "alg-legacy",
+                include_bytes!("../test/metadata/manifest.alg-legacy.sig"),
+            ),
+            // Legacy signature with the prehashed algorithm tag
+            (
+                "alg-prehashed",
+                include_bytes!("../test/metadata/manifest.alg-prehashed.sig"),
+            ),
+            // Trusted comment changed after signing
+            (
+                "comment",
+                include_bytes!("../test/metadata/manifest.comment.sig"),
+            ),
+            // Global signature of another trusted comment
+            (
+                "global",
+                include_bytes!("../test/metadata/manifest.global.sig"),
+            ),
+        ];
+        let keys = keys(1).unwrap();
+        let prehash = Blake2b::digest(MANIFEST);
+        for (name, vector) in vectors {
+            let text = String::from_utf8_lossy(vector);
+            let key = keys.get(&text).unwrap();
+            let sig = SignatureBox::from_string(&text).unwrap();
+            assert!(
+                verify_prehashed(key, &sig, &prehash).is_err(),
+                "{} accepted",
+                name
+            );
+            match verify(&keys, &[*vector]) {
+                Err(Error::InvalidSignature { .. }) => (),
+                r => panic!("{} accepted: {:?}", name, r),
+            }
+        }
+    }
+
+    #[test]
+    fn verify_threshold() {
+        let keys = keys(2).unwrap();
+        assert!(verify(&keys, &[SIG, SIG_SECOND]).is_ok());
+        assert!(verify(&keys, &[SIG_SECOND, SIG]).is_ok());
+        not_enough(verify(&keys, &[SIG]), 1);
+        // Signatures made with the same key only count once
+        not_enough(verify(&keys, &[SIG, SIG]), 1);
+        // Signatures that can not be decoded do not prevent verification
+        let sig = String::from_utf8_lossy(SIG);
+        let truncated = sig.lines().take(2).collect::<Vec<_>>().join("\n");
+        assert!(verify(&keys, &[truncated.as_bytes(), SIG, SIG_SECOND]).is_ok());
+        not_enough(verify(&keys, &[SIG_SECOND, truncated.as_bytes()]), 1);
+
+        assert_eq!(keys.signers(&sigs(&[SIG])), 1);
+        assert_eq!(keys.signers(&sigs(&[SIG, SIG])), 1);
+        assert_eq!(keys.signers(&sigs(&[SIG, SIG_SECOND])), 2);
+    }
+
+    /// Fetch signatures from the given files, recording the names requested
+    fn fetch(keys: &Keys, files: &[(&str, &[u8])]) -> (Vec<String>, Vec<String>) {
+        let mut requested = Vec::new();
+        let fetched = keys
+            .fetch_signatures("manifest.sig", |name| -> std::result::Result<_, ()> {
+                requested.push(String::from(name));
+                Ok(files
+                    .iter()
+                    .find(|(f, _)| *f == name)
+                    .map(|(_, s)| s.to_vec()))
+            })
+            .unwrap();
+        (requested, fetched.into_iter().map(|(n, _)| n).collect())
+    }
+
+    #[test]
+    fn fetch_until_threshold() {
+        let files: &[(&str, &[u8])] = &[
+            ("manifest.sig", SIG),
+            ("manifest.sig.1", SIG_SECOND),
+            ("manifest.sig.2", SIG),
+        ];
+        // Additional signatures are not needed
+        let (requested, fetched) = fetch(&keys(1).unwrap(), files);
+        assert_eq!(requested, vec!["manifest.sig"]);
+        assert_eq!(fetched, vec!["manifest.sig"]);
+        // No more signatures are fetched once the threshold is reached
+        let (requested, fetched) = fetch(&keys(2).unwrap(), files);
+        assert_eq!(requested, vec!["manifest.sig", "manifest.sig.1"]);
+        assert_eq!(fetched, requested);
+
+        // Signatures made with the same key do not count
+        let files: &[(&str, &[u8])] = &[
+            ("manifest.sig", SIG),
+            ("manifest.sig.1", SIG),
+            ("manifest.sig.2", SIG_SECOND),
+        ];
+        let (requested, fetched) = fetch(&keys(2).unwrap(), files);
+        assert_eq!(requested.len(), 3);
+        assert_eq!(fetched.len(), 3);
+
+        // Missing signatures are skipped
+        let (requested, fetched) = fetch(&keys(2).unwrap(), &files[1..]);
+        assert_eq!(requested.len(), 3);
+        assert_eq!(fetched, vec!["manifest.sig.1", "manifest.sig.2"]);
+    }
+
+    #[test]
+    fn verify_unknown_key() {
+        let config = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/client/config");
+        let keys = Keys::load(&config, 1).unwrap();
+        not_enough(verify(&keys, &[SIG_SECOND]), 0);
+        assert_eq!(keys.signers(&sigs(&[SIG_SECOND])), 0);
+    }
+
+    #[test]
+    fn revocation() {
+        let dir = test_dir("keys-revocation");
+        let state = &format!("{}/revoked", dir);
+        let list: &[u8] = include_bytes!("../test/metadata/revoked");
+        let list_sig: &[u8] = include_bytes!("../test/metadata/revoked.sig");
+
+        let keys = keys(1).unwrap();
+        // The revocation list must be signed with the trusted keys
+        let invalid = keys.revoke("revoked", b"revoked = []", &sigs(&[list_sig]), Some(state));
+        assert!(invalid.is_err());
+        assert!(!Path::new(state).exists());
+
+        // Without a state file, keys are only revoked for this run
+        let unsaved = self::keys(1).unwrap();
+        unsaved
+            .revoke("revoked", list, &sigs(&[list_sig]), None)
+            .unwrap();
+        not_enough(verify(&unsaved, &[SIG_SECOND]), 0);
+        assert!(!Path::new(state).exists());
+
+        keys.revoke("revoked", list, &sigs(&[list_sig]), Some(state))
+            .unwrap();
+        assert_eq!(fs::read_to_string(state).unwrap(), "E48249A88D15986E\n");
+        assert!(verify(&keys, &[SIG]).is_ok());
+        not_enough(verify(&keys, &[SIG_SECOND]), 0);
+        match keys.get(&String::from_utf8_lossy(SIG_SECOND)) {
+            Err(Error::RevokedKey { .. }) => (),
+            _ => panic!("revoked key used"),
+        }
+
+        // Revoked keys are still revoked on the next run, whatever the list
+        let keys = self::keys(1).unwrap();
+        keys.load_revoked(state).unwrap();
+        not_enough(verify(&keys, &[SIG_SECOND]), 0);
+
+        fs::write(state, "not an ID\n").unwrap();
+        match keys.load_revoked(state) {
+            Err(Error::InvalidState { .. }) => (),
+            r => panic!("invalid state accepted: {:?}", r),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
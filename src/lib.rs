@@ -0,0 +1,127 @@
+// SPDX-License-Identifier: LGPL-2.1-or-later
+// Copyright © 2019 ANSSI. All rights reserved.
+
+//! Library to update CLIP OS systems.
+//!
+//! **WARNING: This is intended to be run only in a CLIP OS system! Running it
+//! as root on a conventional system will result in data loss!**
+//!
+//! The threat model and design for this updater is described in the [security
+//! objectives](https://docs.clip-os.org/clipos/security.html) and [update
+//! model](https://docs.clip-os.org/clipos/updates.html) documentation of the
+//! CLIP OS project.
+//!
+//! An update is split in the following stages, each one available as a method
+//! of [`System`](struct.System.html):
+//!
+//! 1. [`Remote::check_update`](struct.Remote.html#method.check_update):
+//!    fetch and verify the signed manifest describing the latest available
+//!    version
+//! 2. [`System::download`](struct.System.html#method.download): download
+//!    and verify update payloads into the cache folder
+//! 3. [`System::install`](struct.System.html#method.install): install
+//!    verified payloads from the cache folder
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::path::PathBuf;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let (system, remote) = updater::parse(
+//!     PathBuf::from("/usr/lib/updater"),
+//!     PathBuf::from("/etc/updater"),
+//!     String::from("/var/lib/updater"),
+//!     String::from("/var/lib/updater-state"),
+//! )?;
+//!
+//! if let Some(manifest) = remote.check_update(&system)? {
+//!     system.download(&remote, &manifest)?;
+//!     system.install(&manifest.version)?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Errors
+//!
+//! Each stage returns its own error type, re-exported here with its source
+//! error types so that specific failures can be matched on. All of them
+//! implement [`Classify`](trait.Classify.html) to map them to the exit codes
+//! of the updater.
+//!
+//! ```no_run
+//! use updater::{FloorError, SystemError};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let (system, _remote) = updater::parse(
+//! #     "/usr/lib/updater".into(),
+//! #     "/etc/updater".into(),
+//! #     "/var/lib/updater".into(),
+//! #     "/var/lib/updater-state".into(),
+//! # )?;
+//! if let Some(version) = system.staged()? {
+//!     match system.install(&version) {
+//!         Ok(()) => println!("Version {} installed", version),
+//!         Err(SystemError::Floor {
+//!             source: FloorError::BelowFloor { floor, .. },
+//!         }) => println!("Version {} is below the floor {}", version, floor),
+//!         Err(e) => return Err(e.into()),
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+#![forbid(unsafe_code)]
+
+#[macro_use]
+extern crate log;
+extern crate reqwest;
+#[macro_use]
+extern crate serde_derive;
+extern crate base64;
+extern crate futures;
+extern crate hyper;
+extern crate hyper_rustls;
+extern crate libmount;
+extern crate lvm;
+extern crate minisign;
+extern crate nix;
+extern crate os_release;
+extern crate ring;
+extern crate rustls;
+extern crate semver;
+extern crate serde;
+extern crate serde_json;
+extern crate snafu;
+extern crate tokio;
+extern crate toml;
+extern crate untrusted;
+extern crate webpki;
+
+mod config;
+mod error;
+mod floor;
+mod keys;
+mod manifest;
+mod pin;
+mod source;
+mod state;
+mod system;
+
+pub use crate::config::{parse, Bandwidth, Limits, Mirror, Remote, Retry};
+pub use crate::error::{Classify, ErrorClass, Report};
+pub use crate::manifest::{Manifest, ManifestPackage};
+pub use crate::system::{
+    Action, CacheStatus, InstallPlan, Kind, LvStatus, Package, RollbackPlan, RollbackStep, Status,
+    Step, System, UPDATE_READY,
+};
+
+pub use crate::config::Error as ConfigError;
+pub use crate::floor::Error as FloorError;
+pub use crate::keys::Error as KeysError;
+pub use crate::manifest::Error as ManifestError;
+pub use crate::pin::Error as PinError;
+pub use crate::source::Error as SourceError;
+pub use crate::system::Error as SystemError;
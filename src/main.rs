@@ -11,6 +11,8 @@
 //! model](https://docs.clip-os.org/clipos/updates.html) documentation of the
 //! CLIP OS project.
 //!
+//! This is a thin command line interface on top of the `updater` library.
+//!
 //! See also the [README](https://github.com/clipos/src_platform_updater) for
 //! more information.
 
@@ -19,28 +21,22 @@
 extern crate env_logger;
 #[macro_use]
 extern crate log;
-extern crate reqwest;
-#[macro_use]
-extern crate serde_derive;
-extern crate libmount;
-extern crate lvm;
-extern crate minisign;
-extern crate os_release;
 extern crate semver;
-extern crate serde;
-extern crate snafu;
+extern crate serde_json;
 extern crate structopt;
-extern crate toml;
-
-mod config;
-mod system;
+extern crate updater;
 
 use log::LevelFilter;
+use semver::Version;
 use std::fs::OpenOptions;
 use std::path::PathBuf;
 use std::process::exit;
+use std::str::FromStr;
+use structopt::clap;
 use structopt::StructOpt;
 
+use updater::{Classify, Manifest, Remote, Report, Status, System, UPDATE_READY};
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "updater", about = "CLIP OS updater")]
 struct Opt {
@@ -48,7 +44,7 @@ struct Opt {
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     verbose: u8,
 
-    /// Path to system configuration files (config.toml & pubkey)
+    /// Path to system configuration files (config.toml & trusted keys)
     #[structopt(
         short = "c",
         long = "config",
@@ -57,7 +53,8 @@ struct Opt {
     )]
     config: PathBuf,
 
-    /// Path to remote configuration files (remote.toml & rootca.pem)
+    /// Path to remote configuration files (remote.toml, rootca.pem and client
+    /// certificate)
     #[structopt(
         short = "r",
         long = "remote",
@@ -74,6 +71,205 @@ struct Opt {
         default_value = "/var/lib/updater"
     )]
     tmp: String,
+
+    /// Path to the folder keeping the updater state between runs (accepted
+    /// manifest, revoked keys and anti-rollback floor). Must not be cleared
+    /// with the temporary folder.
+    #[structopt(
+        short = "s",
+        long = "state",
+        parse(from_str),
+        default_value = "/var/lib/updater-state"
+    )]
+    state: String,
+
+    /// Output format for reports and errors: human or json
+    #[structopt(
+        short = "o",
+        long = "output",
+        default_value = "human",
+        raw(possible_values = r#"&["human", "json"]"#)
+    )]
+    output: Output,
+
+    /// Only print the actions planned to install an update or to rollback,
+    /// without modifying the system or the updater state. Only supported by
+    /// install, rollback and without any subcommand.
+    #[structopt(short = "n", long = "dry-run")]
+    dry_run: bool,
+
+    /// Started by the update timer: apply the timer download rate limit
+    /// instead of the interactive one
+    #[structopt(long = "timer")]
+    timer: bool,
+
+    /// Update stage to run. Check, download and install an update if none is
+    /// given.
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Check if an update is available without downloading it
+    #[structopt(name = "check")]
+    Check,
+
+    /// Download and verify update payloads without installing them
+    #[structopt(name = "download")]
+    Download,
+
+    /// Install previously downloaded and verified update payloads
+    #[structopt(name = "install")]
+    Install,
+
+    /// Boot back into the previously installed version
+    #[structopt(name = "rollback")]
+    Rollback,
+
+    /// Report installed versions, boot entries and downloaded payloads
+    #[structopt(name = "status")]
+    Status,
+}
+
+/// Output format for reports
+#[derive(Debug, PartialEq)]
+enum Output {
+    Human,
+    Json,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Output, String> {
+        match s {
+            "human" => Ok(Output::Human),
+            "json" => Ok(Output::Json),
+            _ => Err(format!("unknown output format '{}'", s)),
+        }
+    }
+}
+
+/// Log the error, report it in the requested format and exit with the exit
+/// code corresponding to its class
+fn fail<E: Classify>(e: E, output: &Output) -> ! {
+    error!("{}", e);
+    let report = Report::new(&e);
+    if *output == Output::Json {
+        match serde_json::to_string(&report) {
+            Err(e) => error!("Could not serialize error report: {}", e),
+            Ok(s) => println!("{}", s),
+        }
+    }
+    info!("Exiting");
+    exit(report.code);
+}
+
+/// Ask the remote for an update
+fn available(system: &System, remote: &Remote, output: &Output) -> Option<Manifest> {
+    match remote.check_update(system) {
+        Err(e) => fail(e, output),
+        Ok(None) => {
+            info!("No update found");
+            None
+        }
+        Ok(Some(m)) => {
+            info!("Update found: version '{}'", m.version);
+            Some(m)
+        }
+    }
+}
+
+/// Ask the remote for an update and exit early if none is available
+fn check(system: &System, remote: &Remote, output: &Output) -> Manifest {
+    match available(system, remote, output) {
+        None => {
+            info!("Exiting");
+            exit(0);
+        }
+        Some(m) => m,
+    }
+}
+
+/// Print the version of the available update, if any, in the requested
+/// format. Nothing is printed in human format if there is no update.
+fn print_available(manifest: Option<&Manifest>, output: &Output) {
+    let version = manifest.map(|m| m.version.to_string());
+    match (output, version) {
+        (Output::Json, v) => println!("{}", serde_json::json!({ "version": v })),
+        (Output::Human, Some(v)) => println!("{}", v),
+        (Output::Human, None) => (),
+    }
+}
+
+/// Print the system status in the requested format
+fn print_status(status: &Status, output: &Output) {
+    if *output == Output::Json {
+        match serde_json::to_string_pretty(status) {
+            Err(e) => fail(e, output),
+            Ok(s) => println!("{}", s),
+        }
+        return;
+    }
+
+    let yes_no = |b: bool| if b { "yes" } else { "no" };
+
+    println!("OS name:       {}", status.os_name);
+    println!("Version:       {}", status.version);
+    println!("Update ready:  {}", yes_no(status.update_ready));
+    println!();
+    println!("{:<32} {:<24} CURRENT", "LV", "VERSION");
+    for lv in &status.lvs {
+        println!("{:<32} {:<24} {}", lv.name, lv.version, yes_no(lv.current));
+    }
+    println!();
+    println!("EFI BINARY");
+    for efi in &status.efiboot {
+        println!("{}", efi);
+    }
+    println!();
+    println!("{:<10} {:<48} {:>12} SIGNATURE", "CACHED", "PATH", "SIZE");
+    for c in &status.cache {
+        println!(
+            "{:<10} {:<48} {:>12} {}",
+            c.name,
+            c.path,
+            c.size,
+            yes_no(c.signature)
+        );
+    }
+}
+
+/// Install a downloaded update, or only print the planned actions in dry run
+/// mode
+fn install(system: &System, version: &Version, dry_run: bool, output: &Output) {
+    if dry_run {
+        match system.plan(version) {
+            Err(e) => fail(e, output),
+            Ok(plan) => println!("{}", plan),
+        }
+        return;
+    }
+
+    match system.install(version) {
+        Err(e) => fail(e, output),
+        Ok(()) => info!("Successfully updated!"),
+    }
+    update_ready();
+}
+
+/// Mark the system as waiting for a reboot to apply the update
+fn update_ready() {
+    // TODO: Inform the user that an update is ready and a reboot is required
+    // For now we drop an empty file in a specific path in /run
+    // The systemd unit will not trigger if this file exists, thus avoiding repeated
+    // updates in a loop.
+    let marker = UPDATE_READY;
+    match OpenOptions::new().create(true).write(true).open(&marker) {
+        Ok(_f) => debug!("Touched '{}'", &marker),
+        Err(e) => warn!("Could not touch '{}': {}", &marker, e),
+    };
 }
 
 fn main() {
@@ -93,52 +289,98 @@ fn main() {
         .init();
 
     info!("Starting updater");
+    let output = &opt.output;
 
-    let (system, remote) = match config::parse(opt.config, opt.remote, opt.tmp) {
-        Err(e) => {
-            error!("{}", e);
-            info!("Exiting");
-            exit(1);
+    if opt.dry_run {
+        let unsupported = match opt.cmd {
+            Some(Command::Check) => Some("check"),
+            Some(Command::Download) => Some("download"),
+            Some(Command::Status) => Some("status"),
+            _ => None,
+        };
+        if let Some(cmd) = unsupported {
+            clap::Error::with_description(
+                &format!("--dry-run is not supported by the '{}' subcommand", cmd),
+                clap::ErrorKind::ArgumentConflict,
+            )
+            .exit();
         }
+    }
+
+    let (system, mut remote) = match updater::parse(opt.config, opt.remote, opt.tmp, opt.state) {
+        Err(e) => fail(e, output),
         Ok(c) => c,
     };
+    remote.timer = opt.timer;
+    remote.dry_run = opt.dry_run;
 
-    let version = match remote.check_update(&system) {
-        Err(e) => {
-            error!("{}", e);
-            info!("Exiting");
-            exit(1);
+    match opt.cmd {
+        Some(Command::Check) => {
+            let version = available(&system, &remote, output);
+            print_available(version.as_ref(), output);
         }
-        Ok(r) => match r {
-            None => {
-                info!("No update found");
-                info!("Exiting");
-                exit(0);
+        Some(Command::Download) => {
+            let manifest = check(&system, &remote, output);
+            match system.download(&remote, &manifest) {
+                Err(e) => fail(e, output),
+                Ok(()) => info!("Update payloads ready to be installed"),
             }
-            Some(v) => v,
+        }
+        Some(Command::Install) => {
+            let version = match system.staged() {
+                Err(e) => fail(e, output),
+                Ok(None) => {
+                    info!("No downloaded update found");
+                    info!("Exiting");
+                    exit(0);
+                }
+                Ok(Some(v)) => v,
+            };
+            install(&system, &version, opt.dry_run, output);
+        }
+        Some(Command::Status) => match system.status() {
+            Err(e) => fail(e, output),
+            Ok(status) => print_status(&status, output),
         },
-    };
-
-    // Apply update payloads and install the new EFI boot entries
-    match system.update(remote, version) {
-        Err(e) => {
-            error!("{}", e);
-            info!("Exiting");
-            exit(1);
+        Some(Command::Rollback) => {
+            let version = match system.rollback_target() {
+                Err(e) => fail(e, output),
+                Ok(v) => v,
+            };
+            if opt.dry_run {
+                match system.rollback_plan(&version) {
+                    Err(e) => fail(e, output),
+                    Ok(plan) => println!("{}", plan),
+                }
+            } else {
+                match system.rollback(&remote, &version) {
+                    Err(e) => fail(e, output),
+                    Ok(()) => info!("Successfully rolled back to version '{}'", version),
+                }
+                update_ready();
+            }
+        }
+        None => {
+            // Apply update payloads and install the new EFI boot entries
+            let manifest = check(&system, &remote, output);
+            if opt.dry_run {
+                // Nothing is downloaded: the actions can only be planned if
+                // the payloads have already been downloaded
+                match system.staged() {
+                    Err(e) => fail(e, output),
+                    Ok(Some(ref v)) if *v == manifest.version => install(&system, v, true, output),
+                    Ok(_) => println!("Download and install version '{}'", manifest.version),
+                }
+            } else {
+                match system.update(&remote, &manifest) {
+                    Err(e) => fail(e, output),
+                    Ok(()) => info!("Successfully updated!"),
+                }
+                update_ready();
+            }
         }
-        Ok(()) => info!("Successfully updated!"),
     }
 
-    // TODO: Inform the user that an update is ready and a reboot is required
-    // For now we drop an empty file in a specific path in /run
-    // The systemd unit will not trigger if this file exists, thus avoiding repeated
-    // updates in a loop.
-    let marker = "/run/update_ready";
-    match OpenOptions::new().create(true).write(true).open(&marker) {
-        Ok(_f) => debug!("Touched '{}'", &marker),
-        Err(e) => warn!("Could not touch '{}': {}", &marker, e),
-    };
-
     info!("Exiting");
     exit(0);
 }
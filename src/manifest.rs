@@ -0,0 +1,335 @@
+// SPDX-License-Identifier: LGPL-2.1-or-later
+// Copyright © 2019 ANSSI. All rights reserved.
+
+//! Signed update manifest.
+//!
+//! The manifest is served next to its minisign signature as
+//! `<update_url>/<os_name>/manifest` and `manifest.sig`, and signed with the
+//! trusted keys, like the update payloads:
+//!
+//! ```toml
+//! version = "5.0.0-alpha.3"
+//! issued = 1561939200   # seconds since the Unix epoch
+//! expires = 1577836800
+//!
+//! [[package]]
+//! name = "core"
+//! size = 27
+//! sha256 = "<hexadecimal SHA-256 of the payload>"
+//! ```
+//!
+//! To protect against freeze attacks, where an old but validly signed manifest
+//! is served to keep a system from updating, expired manifests are refused, as
+//! well as manifests issued before the last one accepted. The issue time of
+//! the last accepted manifest is kept in a state file.
+
+use semver::Version;
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::{Classify, ErrorClass};
+use crate::keys;
+use crate::keys::Keys;
+use crate::state;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Invalid manifest signature: {}", source))]
+    Signature { source: keys::Error },
+    #[snafu(display("Could not parse manifest: {}", source))]
+    InvalidManifest { source: toml::de::Error },
+    #[snafu(display("Could not parse '{}' as valid version: {}", version, source))]
+    InvalidVersion {
+        version: String,
+        source: semver::SemVerError,
+    },
+    #[snafu(display("Invalid SHA-256 hash for package '{}' in manifest", name))]
+    InvalidHash { name: String },
+    #[snafu(display("Manifest expired at {} (seconds since the Unix epoch)", expires))]
+    Expired { expires: u64 },
+    #[snafu(display(
+        "Manifest issued at {} is older than the last accepted one, issued at {}",
+        issued,
+        last
+    ))]
+    Outdated { issued: u64, last: u64 },
+    #[snafu(display("Could not read or write manifest state '{}': {}", filename.display(), source))]
+    State {
+        filename: PathBuf,
+        source: io::Error,
+    },
+    #[snafu(display("Invalid manifest state in '{}'", filename.display()))]
+    InvalidState { filename: PathBuf },
+    #[snafu(display("Package '{}' is missing from the manifest", name))]
+    MissingPackage { name: String },
+}
+
+impl Classify for Error {
+    fn class(&self) -> ErrorClass {
+        match self {
+            Error::Expired { .. } | Error::Outdated { .. } => ErrorClass::MetadataExpired,
+            Error::State { .. } | Error::InvalidState { .. } => ErrorClass::Io,
+            Error::Signature { source } => source.class(),
+            _ => ErrorClass::Signature,
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Used to parse manifests
+#[derive(Deserialize, Debug)]
+struct TomlManifest {
+    version: String,
+    issued: u64,
+    expires: u64,
+    #[serde(default)]
+    package: Vec<TomlManifestPackage>,
+}
+
+/// Used to parse manifests
+#[derive(Deserialize, Debug)]
+struct TomlManifestPackage {
+    name: String,
+    size: u64,
+    sha256: String,
+}
+
+/// Verified description of an update
+#[derive(Debug)]
+pub struct Manifest {
+    pub version: Version,
+    /// Issue time in seconds since the Unix epoch
+    pub issued: u64,
+    /// Expiry time in seconds since the Unix epoch
+    pub expires: u64,
+    pub packages: Vec<ManifestPackage>,
+}
+
+/// Expected size and hash of an update payload
+#[derive(Debug)]
+pub struct ManifestPackage {
+    pub name: String,
+    pub size: u64,
+    pub sha256: Vec<u8>,
+}
+
+impl Manifest {
+    /// Verify the manifest signatures with the trusted keys, then parse it
+    /// and make sure that it has not expired and that it was not issued before
+    /// the last accepted manifest, if any
+    pub fn verify(
+        url: &str,
+        manifest: &[u8],
+        signatures: &[(String, Vec<u8>)],
+        keys: &Keys,
+        last_issued: Option<u64>,
+    ) -> Result<Manifest> {
+        keys.verify(url, manifest, signatures).context(Signature)?;
+
+        let m: TomlManifest =
+            toml::from_str(&String::from_utf8_lossy(manifest)).context(InvalidManifest)?;
+        let version = Version::parse(&m.version).context(InvalidVersion {
+            version: m.version.as_str(),
+        })?;
+
+        let mut packages = Vec::new();
+        for p in m.package {
+            let sha256 = from_hex(&p.sha256)
+                .filter(|h| h.len() == 32)
+                .ok_or_else(|| Error::InvalidHash {
+                    name: p.name.clone(),
+                })?;
+            packages.push(ManifestPackage {
+                name: p.name,
+                size: p.size,
+                sha256,
+            });
+        }
+
+        let manifest = Manifest {
+            version,
+            issued: m.issued,
+            expires: m.expires,
+            packages,
+        };
+        if manifest.expired() {
+            return Err(Error::Expired {
+                expires: manifest.expires,
+            });
+        }
+        if let Some(last) = last_issued {
+            if manifest.issued < last {
+                return Err(Error::Outdated {
+                    issued: manifest.issued,
+                    last,
+                });
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Whether the manifest has expired
+    pub fn expired(&self) -> bool {
+        expired(self.expires)
+    }
+
+    /// Expected size and hash for the given package
+    pub fn package(&self, name: &str) -> Result<&ManifestPackage> {
+        self.packages
+            .iter()
+            .find(|p| p.name == name)
+            .ok_or_else(|| Error::MissingPackage {
+                name: String::from(name),
+            })
+    }
+}
+
+/// Read the issue time of the last accepted manifest from the state file, if
+/// any manifest has been accepted yet
+pub fn last_issued(filename: &str) -> Result<Option<u64>> {
+    let state = match fs::read_to_string(filename) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        r => r.context(State { filename })?,
+    };
+    let issued = state
+        .trim()
+        .parse()
+        .ok()
+        .context(InvalidState { filename })?;
+    Ok(Some(issued))
+}
+
+/// Record the issue time of an accepted manifest in the state file
+pub fn save_issued(filename: &str, issued: u64) -> Result<()> {
+    state::write(filename, format!("{}\n", issued).as_bytes()).context(State { filename })
+}
+
+/// Whether signed metadata expiring at the given time, in seconds since the
+/// Unix epoch, has expired
+pub fn expired(expires: u64) -> bool {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() >= expires)
+        .unwrap_or(true)
+}
+
+/// Decode an hexadecimal string
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+/// Encode bytes as an hexadecimal string
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_dir;
+    use std::path::Path;
+
+    const MANIFEST: &[u8] = include_bytes!("../webroot/update/v1/clipos/manifest");
+    const SIG: &[u8] = include_bytes!("../webroot/update/v1/clipos/manifest.sig");
+
+    fn keys() -> Keys {
+        let config = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/client/config");
+        Keys::load(&config, 1).unwrap()
+    }
+
+    fn verify(manifest: &[u8], sig: &[u8], last_issued: Option<u64>) -> Result<Manifest> {
+        let signatures = [(String::from("manifest.sig"), sig.to_vec())];
+        Manifest::verify("manifest", manifest, &signatures, &keys(), last_issued)
+    }
+
+    #[test]
+    fn valid_manifest() {
+        let m = verify(MANIFEST, SIG, None).unwrap();
+        assert_eq!(m.version, Version::parse("5.0.0-alpha.3").unwrap());
+        assert_eq!(m.issued, 1_561_939_200);
+        assert!(!m.expired());
+        let core = m.package("core").unwrap();
+        assert_eq!(core.size, 27);
+        assert_eq!(
+            to_hex(&core.sha256),
+            "e1bc7069fae2e81d54504f01ed4ed468c5141df9d8d32f84249395e31b726f6c"
+        );
+        match m.package("missing") {
+            Err(Error::MissingPackage { .. }) => (),
+            _ => panic!("missing package found"),
+        }
+    }
+
+    #[test]
+    fn tampered_manifest() {
+        let tampered = String::from_utf8_lossy(MANIFEST).replace("size = 27", "size = 28");
+        match verify(tampered.as_bytes(), SIG, None) {
+            Err(Error::Signature { .. }) => (),
+            r => panic!("tampered manifest accepted: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn expired_manifest() {
+        let manifest = include_bytes!("../test/metadata/manifest.expired");
+        let sig = include_bytes!("../test/metadata/manifest.expired.sig");
+        match verify(manifest, sig, None) {
+            Err(Error::Expired { expires }) => assert_eq!(expires, 1_561_939_201),
+            r => panic!("expired manifest accepted: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn outdated_manifest() {
+        match verify(MANIFEST, SIG, Some(1_561_939_201)) {
+            Err(Error::Outdated { issued, last }) => {
+                assert_eq!((issued, last), (1_561_939_200, 1_561_939_201))
+            }
+            r => panic!("outdated manifest accepted: {:?}", r),
+        }
+        // The same manifest may be served again
+        assert!(verify(MANIFEST, SIG, Some(1_561_939_200)).is_ok());
+        assert!(verify(MANIFEST, SIG, Some(1_561_939_199)).is_ok());
+    }
+
+    #[test]
+    fn expiry() {
+        assert!(expired(0));
+        assert!(expired(1_561_939_200));
+        assert!(!expired(u64::max_value()));
+    }
+
+    #[test]
+    fn issued_state() {
+        let dir = test_dir("manifest-state");
+        let state = &format!("{}/manifest.issued", dir);
+        assert_eq!(last_issued(state).unwrap(), None);
+        save_issued(state, 1_561_939_200).unwrap();
+        assert_eq!(last_issued(state).unwrap(), Some(1_561_939_200));
+
+        fs::write(state, "not a time\n").unwrap();
+        match last_issued(state) {
+            Err(Error::InvalidState { .. }) => (),
+            r => panic!("invalid state accepted: {:?}", r),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(from_hex("00ff7f"), Some(vec![0x00, 0xff, 0x7f]));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(to_hex(&[0x00, 0xff, 0x7f]), "00ff7f");
+    }
+}
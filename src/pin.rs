@@ -0,0 +1,739 @@
+// SPDX-License-Identifier: LGPL-2.1-or-later
+// Copyright © 2019 ANSSI. All rights reserved.
+
+//! Server public key pinning.
+//!
+//! Pins are checked by `PinVerifier`, a rustls certificate verifier that
+//! accepts a server if its certificate chains to the root CA and if the
+//! SHA-256 hash of the SubjectPublicKeyInfo of the server certificate, or of
+//! a certificate it chains to, matches one of the pins. The handshake is
+//! aborted before any request is sent otherwise.
+//!
+//! The verifier can not be given to the shared reqwest client: reqwest 0.9
+//! neither accepts a preconfigured TLS configuration nor a custom verifier.
+//! `use_preconfigured_tls` only comes with reqwest 0.10, which requires
+//! async/await and thus a newer compiler than the one the updater is built
+//! with (see `rust-toolchain`). Until then, pinned requests are made with the
+//! hyper client reqwest is built on, over hyper-rustls and hyper-proxy, from
+//! the same `Transport` settings as the shared client. Responses are handed
+//! out as reqwest responses so that callers handle both clients alike.
+
+use futures::sync::{mpsc, oneshot};
+use futures::{Future, Sink, Stream};
+use hyper::client::HttpConnector;
+use hyper::{Body, Chunk, Response};
+use hyper_proxy::{Proxy, ProxyConnector};
+use hyper_rustls::HttpsConnector;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, PROXY_AUTHORIZATION};
+use reqwest::StatusCode;
+use ring::digest;
+use rustls::internal::pemfile;
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::TlsConnector;
+use untrusted::Input;
+use webpki::trust_anchor_util::cert_der_as_trust_anchor;
+use webpki::{EndEntityCert, TLSServerTrustAnchors, TrustAnchor};
+
+use crate::config::Transport;
+use crate::error::{Classify, ErrorClass};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Invalid SPKI SHA-256 pin '{}'", pin))]
+    InvalidPin { pin: String },
+    #[snafu(display("Could not load root certificate from '{}'", filename.display()))]
+    InvalidRootCa { filename: PathBuf },
+    #[snafu(display("Could not load client certificate and key from '{}'", filename.display()))]
+    InvalidIdentity { filename: PathBuf },
+    #[snafu(display("Invalid proxy '{}' for the pinned HTTPS client", url))]
+    InvalidProxy { url: String },
+    #[snafu(display("Could not start the pinned HTTPS client: {}", source))]
+    Runtime { source: io::Error },
+    #[snafu(display("Only HTTPS URLs may be used with pinned public keys: '{}'", url))]
+    NotHttps { url: String },
+    #[snafu(display("Invalid HTTP request to '{}': {}", url, source))]
+    InvalidRequest {
+        url: String,
+        source: hyper::http::Error,
+    },
+    #[snafu(display(
+        "Public key of '{}' does not match any pin, presented key hash: '{}'",
+        url,
+        presented
+    ))]
+    Mismatch { url: String, presented: String },
+    #[snafu(display("HTTP request to '{}' failed: {}", url, source))]
+    Request { url: String, source: hyper::Error },
+    #[snafu(display("HTTP request to '{}' timed out", url))]
+    Timeout { url: String },
+    #[snafu(display("HTTP request to '{}' failed with status {}", url, status))]
+    Status { url: String, status: StatusCode },
+}
+
+impl Classify for Error {
+    fn class(&self) -> ErrorClass {
+        match self {
+            Error::InvalidPin { .. }
+            | Error::InvalidRootCa { .. }
+            | Error::InvalidIdentity { .. }
+            | Error::InvalidProxy { .. }
+            | Error::Runtime { .. }
+            | Error::NotHttps { .. }
+            | Error::InvalidRequest { .. } => ErrorClass::Config,
+            _ => ErrorClass::Network,
+        }
+    }
+
+    fn transient(&self) -> bool {
+        match self {
+            // Connection errors, timeouts and server errors
+            Error::Request { .. } | Error::Timeout { .. } => true,
+            Error::Status { status, .. } => status.is_server_error(),
+            _ => false,
+        }
+    }
+}
+
+impl Error {
+    /// Whether the requested file does not exist on the server
+    pub fn not_found(&self) -> bool {
+        match self {
+            Error::Status { status, .. } => *status == StatusCode::NOT_FOUND,
+            _ => false,
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Prefix of pins, as used by HPKP
+const PREFIX: &str = "sha256/";
+
+/// Signature algorithms accepted in server certificate chains, as in rustls
+static SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// HTTPS client only accepting servers presenting a pinned public key
+pub struct Client {
+    client: hyper::Client<ProxyConnector<HttpsConnector<HttpConnector>>>,
+    /// Runs the connections of the client, requests are waited for from the
+    /// calling thread
+    runtime: tokio::runtime::Runtime,
+    /// Hash of the last rejected server key, to report it
+    presented: Arc<Mutex<Option<Vec<u8>>>>,
+    headers: HeaderMap,
+    timeout: Duration,
+}
+
+/// Certificate verifier accepting only pinned server keys
+struct PinVerifier {
+    pins: Vec<Vec<u8>>,
+    presented: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> std::result::Result<ServerCertVerified, TLSError> {
+        let now = webpki::Time::try_from(SystemTime::now())
+            .map_err(|_| TLSError::FailedToGetCurrentTime)?;
+        self.verify_at(roots, presented_certs, dns_name, now)
+    }
+}
+
+impl PinVerifier {
+    /// Verify the server certificate chain and key at the given time
+    fn verify_at(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: webpki::DNSNameRef,
+        now: webpki::Time,
+    ) -> std::result::Result<ServerCertVerified, TLSError> {
+        // The certificate chain must be valid in the first place
+        let (end, chain) = presented_certs
+            .split_first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        let cert = EndEntityCert::from(Input::from(&end.0[..])).map_err(TLSError::WebPKIError)?;
+        let chain: Vec<Input> = chain.iter().map(|c| Input::from(&c.0[..])).collect();
+        let anchors: Vec<TrustAnchor> = roots.roots.iter().map(|r| r.to_trust_anchor()).collect();
+        cert.verify_is_valid_tls_server_cert(
+            SIG_ALGS,
+            &TLSServerTrustAnchors(&anchors),
+            &chain,
+            now,
+        )
+        .map_err(TLSError::WebPKIError)?;
+        cert.verify_is_valid_for_dns_name(dns_name)
+            .map_err(TLSError::WebPKIError)?;
+
+        let server =
+            cert_der_as_trust_anchor(Input::from(&end.0[..])).map_err(TLSError::WebPKIError)?;
+        let hash = spki_hash(server.spki);
+        if self.pins.contains(&hash) {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        // Otherwise, the chain must also be valid when only the pinned keys
+        // among the presented and root certificates are trusted: a pinned
+        // certificate merely sent along with the chain is not enough
+        let mut pinned = Vec::new();
+        for c in &chain {
+            if let Ok(anchor) = cert_der_as_trust_anchor(*c) {
+                pinned.push(anchor);
+            }
+        }
+        pinned.extend(roots.roots.iter().map(|r| r.to_trust_anchor()));
+        pinned.retain(|a| self.pins.contains(&spki_hash(a.spki)));
+        if !pinned.is_empty()
+            && cert
+                .verify_is_valid_tls_server_cert(
+                    SIG_ALGS,
+                    &TLSServerTrustAnchors(&pinned),
+                    &chain,
+                    now,
+                )
+                .is_ok()
+        {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        if let Ok(mut p) = self.presented.lock() {
+            *p = Some(hash);
+        }
+        Err(TLSError::General(String::from(
+            "server public key is not pinned",
+        )))
+    }
+}
+
+/// SHA-256 hash of a DER encoded SubjectPublicKeyInfo, given its content as
+/// exposed by webpki, without the enclosing SEQUENCE tag and length
+fn spki_hash(spki: &[u8]) -> Vec<u8> {
+    let mut header = vec![0x30];
+    if spki.len() < 0x80 {
+        header.push(spki.len() as u8);
+    } else {
+        let len: Vec<u8> = spki
+            .len()
+            .to_be_bytes()
+            .iter()
+            .cloned()
+            .skip_while(|&b| b == 0)
+            .collect();
+        header.push(0x80 | len.len() as u8);
+        header.extend(len);
+    }
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(&header);
+    ctx.update(spki);
+    ctx.finish().as_ref().to_vec()
+}
+
+impl Client {
+    /// Parse `sha256/<base64>` pins and build a client using the root CA,
+    /// client certificate, proxy, headers and timeouts of the transport
+    pub fn new(pins: &[String], transport: &Transport) -> Result<Client> {
+        let mut hashes = Vec::new();
+        for pin in pins {
+            let hash = base64::decode(pin.trim_start_matches(PREFIX))
+                .ok()
+                .filter(|h| h.len() == digest::SHA256.output_len)
+                .context(InvalidPin { pin: pin.as_str() })?;
+            hashes.push(hash);
+        }
+
+        let mut config = ClientConfig::new();
+        let mut rootca = transport.rootca.as_ref().map_or(&[][..], |r| &r[..]);
+        match config.root_store.add_pem_file(&mut rootca) {
+            Ok((valid, _)) if valid > 0 => (),
+            _ => {
+                return Err(Error::InvalidRootCa {
+                    filename: transport.remote.join("rootca.pem"),
+                })
+            }
+        }
+        if let Some(pem) = &transport.identity {
+            let certs = pemfile::certs(&mut &pem[..]).ok().filter(|c| !c.is_empty());
+            let key = pemfile::pkcs8_private_keys(&mut &pem[..])
+                .ok()
+                .and_then(|k| k.into_iter().next())
+                .or_else(|| {
+                    pemfile::rsa_private_keys(&mut &pem[..])
+                        .ok()
+                        .and_then(|k| k.into_iter().next())
+                });
+            match (certs, key) {
+                (Some(c), Some(k)) => config.set_single_client_cert(c, k),
+                _ => {
+                    return Err(Error::InvalidIdentity {
+                        filename: transport.remote.join("client.pem"),
+                    })
+                }
+            }
+        }
+        let presented = Arc::new(Mutex::new(None));
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(PinVerifier {
+                pins: hashes,
+                presented: presented.clone(),
+            }));
+
+        let runtime = tokio::runtime::Builder::new()
+            .core_threads(1)
+            .name_prefix("updater-pin-")
+            .build()
+            .context(Runtime)?;
+        let mut http = HttpConnector::new(1);
+        http.enforce_http(false);
+        http.set_connect_timeout(Some(transport.connect_timeout));
+        // Tunnels through the proxy are secured with the same configuration
+        let tls = TlsConnector::from(Arc::new(config.clone()));
+        let mut connector = ProxyConnector::unsecured(HttpsConnector::from((http, config)));
+        connector.set_tls(Some(tls));
+        if let Some(p) = &transport.proxy {
+            let url = p.url.as_str();
+            let settings = p.clone();
+            let mut proxy = Proxy::new(
+                move |_: Option<&str>, host: Option<&str>, _: Option<u16>| {
+                    host.map_or(false, |h| !settings.bypass(h))
+                },
+                url.parse().ok().context(InvalidProxy { url })?,
+            );
+            if let Some(auth) = p.authorization() {
+                let value = HeaderValue::from_str(&auth)
+                    .ok()
+                    .context(InvalidProxy { url })?;
+                proxy.set_header(PROXY_AUTHORIZATION, value);
+            }
+            connector.add_proxy(proxy);
+        }
+        let client = hyper::Client::builder()
+            .executor(runtime.executor())
+            .build(connector);
+
+        Ok(Client {
+            client,
+            runtime,
+            presented,
+            headers: transport.headers.clone(),
+            timeout: transport.timeout,
+        })
+    }
+
+    /// Send a GET request for url with the default headers and the given
+    /// ones. Error statuses are returned as responses, see `error_for_status`.
+    pub fn get(&self, url: &str, headers: &[(HeaderName, &str)]) -> Result<reqwest::Response> {
+        if !url.starts_with("https://") {
+            return Err(Error::NotHttps {
+                url: String::from(url),
+            });
+        }
+        let mut builder = hyper::Request::get(url);
+        for (name, value) in self.headers.iter() {
+            builder.header(name, value);
+        }
+        for (name, value) in headers {
+            builder.header(name, *value);
+        }
+        let req = builder
+            .body(Body::empty())
+            .context(InvalidRequest { url })?;
+
+        if let Ok(mut p) = self.presented.lock() {
+            *p = None;
+        }
+        let res = self.wait(self.client.request(req)).map_err(|e| {
+            let presented = self.presented.lock().ok().and_then(|mut p| p.take());
+            match (presented, e.into_inner()) {
+                (Some(hash), _) => Error::Mismatch {
+                    url: String::from(url),
+                    presented: format!("{}{}", PREFIX, base64::encode(&hash)),
+                },
+                (None, Some(e)) => Error::Request {
+                    url: String::from(url),
+                    source: e,
+                },
+                (None, None) => Error::Timeout {
+                    url: String::from(url),
+                },
+            }
+        })?;
+        debug!("Public key of the server for '{}' is pinned", url);
+
+        // The body is received within the runtime, where each part must
+        // arrive before the timeout
+        let (parts, body) = res.into_parts();
+        let (tx, rx) = mpsc::channel(0);
+        let chunks = tokio::timer::Timeout::new(body, self.timeout).then(|r| {
+            Ok(r.map_err(|e| match e.into_inner() {
+                Some(e) => io::Error::new(io::ErrorKind::Other, e),
+                None => io::Error::new(io::ErrorKind::TimedOut, "download timed out"),
+            }))
+        });
+        self.runtime
+            .executor()
+            .spawn(tx.send_all(chunks).then(|_| Ok(())));
+        let body = rx.then(|r| match r {
+            Ok(r) => r,
+            Err(()) => Err(io::Error::new(io::ErrorKind::Other, "download aborted")),
+        });
+        let body: Box<dyn Stream<Item = Chunk, Error = io::Error> + Send> = Box::new(body);
+        Ok(reqwest::Response::from(Response::from_parts(parts, body)))
+    }
+
+    /// Wait for a future run by the runtime, giving up after the timeout
+    fn wait<F>(
+        &self,
+        future: F,
+    ) -> std::result::Result<F::Item, tokio::timer::timeout::Error<F::Error>>
+    where
+        F: Future + Send + 'static,
+        F::Item: Send,
+        F::Error: Send,
+    {
+        oneshot::spawn(
+            tokio::timer::Timeout::new(future, self.timeout),
+            &self.runtime.executor(),
+        )
+        .wait()
+    }
+}
+
+/// Turn statuses other than success into errors. Responses of the pinned
+/// client do not know their URL, which reqwest would report. Redirections are
+/// not followed, as the pins would not be checked again for the new location:
+/// their body must not be taken for the requested file.
+pub fn error_for_status(res: reqwest::Response, url: &str) -> Result<reqwest::Response> {
+    let status = res.status();
+    if !status.is_success() {
+        return Err(Error::Status {
+            url: String::from(url),
+            status,
+        });
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProxySettings;
+    use reqwest::Url;
+    use rustls::{NoClientAuth, ServerConfig, ServerSession};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// DER encoding of the first certificate of a PEM file
+    fn der_of(pem: &[u8]) -> Vec<u8> {
+        pemfile::certs(&mut &pem[..]).unwrap().remove(0).0
+    }
+
+    fn server_cert() -> Vec<u8> {
+        der_of(include_bytes!("../test/server/update.clip-os.org.pem"))
+    }
+
+    fn isrg_root_x2() -> Vec<u8> {
+        der_of(include_bytes!("../test/pin/isrg-root-x2.pem"))
+    }
+
+    /// Base64 encoded SHA-256 hash of the SubjectPublicKeyInfo of a
+    /// certificate
+    fn pin_of(cert: &[u8]) -> String {
+        let anchor = cert_der_as_trust_anchor(Input::from(cert)).unwrap();
+        base64::encode(&spki_hash(anchor.spki))
+    }
+
+    /// Verify the test server certificate chain with the given pins, at a
+    /// time when the test certificates are valid (2020-01-01)
+    fn verify(pins: &[&str]) -> (std::result::Result<(), TLSError>, Option<Vec<u8>>) {
+        verify_at(pins, &[server_cert()], 1_577_836_800)
+    }
+
+    /// Verify the given certificate chain with the given pins at the given
+    /// time, in seconds since the Unix epoch
+    fn verify_at(
+        pins: &[&str],
+        certs: &[Vec<u8>],
+        time: u64,
+    ) -> (std::result::Result<(), TLSError>, Option<Vec<u8>>) {
+        let presented = Arc::new(Mutex::new(None));
+        let verifier = PinVerifier {
+            pins: pins.iter().map(|p| base64::decode(p).unwrap()).collect(),
+            presented: presented.clone(),
+        };
+        let mut roots = RootCertStore::empty();
+        roots
+            .add_pem_file(&mut &include_bytes!("../test/server/rootCA.pem")[..])
+            .unwrap();
+        let certs: Vec<Certificate> = certs.iter().cloned().map(Certificate).collect();
+        let name = webpki::DNSNameRef::try_from_ascii_str("update.clip-os.org").unwrap();
+        let now = webpki::Time::from_seconds_since_unix_epoch(time);
+        let res = verifier.verify_at(&roots, &certs, name, now).map(|_| ());
+        let presented = presented.lock().unwrap().take();
+        (res, presented)
+    }
+
+    // Expected pins are computed with openssl, as documented in the README
+
+    #[test]
+    fn spki_of_server_certificate() {
+        assert_eq!(
+            pin_of(&server_cert()),
+            "SMuIvYACqojQI8mr9THpHy+ewT4lmMg04Ip6NtG4uwo="
+        );
+    }
+
+    #[test]
+    fn spki_of_rsa_root_certificate() {
+        // The SubjectPublicKeyInfo of a RSA key uses a long form length
+        let cert = der_of(include_bytes!("../test/server/rootCA.pem"));
+        assert_eq!(
+            pin_of(&cert),
+            "M+wHwxLpvf4/kHSN8ZDCKCFyCHAU5snb/bdqmdYKfA8="
+        );
+    }
+
+    #[test]
+    fn spki_of_ecdsa_root_certificate() {
+        assert_eq!(
+            pin_of(&isrg_root_x2()),
+            "diGVwiVYbubAI3RW4hB9xU8e/CH2GnkuvVFZE8zmgzI="
+        );
+    }
+
+    #[test]
+    fn verifier_accepts_pinned_key() {
+        let (res, presented) = verify(&[
+            "diGVwiVYbubAI3RW4hB9xU8e/CH2GnkuvVFZE8zmgzI=",
+            "SMuIvYACqojQI8mr9THpHy+ewT4lmMg04Ip6NtG4uwo=",
+        ]);
+        assert!(res.is_ok());
+        assert_eq!(presented, None);
+    }
+
+    #[test]
+    fn verifier_accepts_pinned_root_key() {
+        let (res, presented) = verify(&["M+wHwxLpvf4/kHSN8ZDCKCFyCHAU5snb/bdqmdYKfA8="]);
+        assert!(res.is_ok());
+        assert_eq!(presented, None);
+    }
+
+    #[test]
+    fn verifier_rejects_other_key() {
+        let (res, presented) = verify(&["diGVwiVYbubAI3RW4hB9xU8e/CH2GnkuvVFZE8zmgzI="]);
+        assert!(res.is_err());
+        assert_eq!(
+            presented.map(|h| base64::encode(&h)),
+            Some(String::from("SMuIvYACqojQI8mr9THpHy+ewT4lmMg04Ip6NtG4uwo="))
+        );
+    }
+
+    #[test]
+    fn verifier_rejects_pinned_certificate_outside_chain() {
+        // The server certificate does not chain to the pinned one
+        let (res, presented) = verify_at(
+            &["diGVwiVYbubAI3RW4hB9xU8e/CH2GnkuvVFZE8zmgzI="],
+            &[server_cert(), isrg_root_x2()],
+            1_577_836_800,
+        );
+        assert!(res.is_err());
+        assert_eq!(
+            presented.map(|h| base64::encode(&h)),
+            Some(String::from("SMuIvYACqojQI8mr9THpHy+ewT4lmMg04Ip6NtG4uwo="))
+        );
+    }
+
+    #[test]
+    fn verifier_rejects_expired_certificate() {
+        // The test certificates expire on 2029-07-16
+        let (res, presented) = verify_at(
+            &["SMuIvYACqojQI8mr9THpHy+ewT4lmMg04Ip6NtG4uwo="],
+            &[server_cert()],
+            1_893_456_000,
+        );
+        assert!(res.is_err());
+        assert_eq!(presented, None);
+    }
+
+    /// Read the head of an HTTP request, up to the empty line
+    fn read_head<R: Read>(r: &mut R) -> io::Result<String> {
+        let mut head = Vec::new();
+        let mut byte = [0u8];
+        while !head.ends_with(b"\r\n\r\n") {
+            r.read_exact(&mut byte)?;
+            head.push(byte[0]);
+        }
+        Ok(String::from_utf8_lossy(&head).into_owned())
+    }
+
+    /// Start a proxy accepting a single CONNECT request, and then serving
+    /// a response with the test server certificate through the tunnel
+    /// itself. Returns the CONNECT request and the request made through the
+    /// tunnel, if the handshake succeeded.
+    fn proxy() -> (ProxySettings, thread::JoinHandle<(String, Option<String>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let connect = read_head(&mut sock).unwrap();
+            sock.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .unwrap();
+
+            let mut config = ServerConfig::new(NoClientAuth::new());
+            let certs =
+                pemfile::certs(&mut &include_bytes!("../test/server/update.clip-os.org.pem")[..])
+                    .unwrap();
+            let key = pemfile::pkcs8_private_keys(
+                &mut &include_bytes!("../test/server/update.clip-os.org-key.pem")[..],
+            )
+            .unwrap()
+            .remove(0);
+            config.set_single_cert(certs, key).unwrap();
+            let mut session = ServerSession::new(&Arc::new(config));
+            let mut tls = rustls::Stream::new(&mut session, &mut sock);
+            let request = read_head(&mut tls).ok();
+            if request.is_some() {
+                tls.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nupdate")
+                    .unwrap();
+                tls.flush().unwrap();
+            }
+            (connect, request)
+        });
+        let settings = ProxySettings {
+            url: Url::parse(&url).unwrap(),
+            credentials: Some((String::from("user"), String::from("pass"))),
+            no_proxy: vec![String::from("clip-os.test")],
+        };
+        (settings, server)
+    }
+
+    /// Pinned client for the given pins, using the given proxy. The test
+    /// certificates must be valid, see above.
+    fn client(pins: &[&str], proxy: ProxySettings) -> Client {
+        let mut headers = HeaderMap::new();
+        headers.insert("clipos-version", HeaderValue::from_static("5.0.0"));
+        let transport = Transport {
+            rootca: Some(include_bytes!("../test/server/rootCA.pem").to_vec()),
+            identity: None,
+            headers,
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(10),
+            proxy: Some(proxy),
+            remote: PathBuf::new(),
+        };
+        let pins: Vec<String> = pins.iter().map(|p| format!("{}{}", PREFIX, p)).collect();
+        Client::new(&pins, &transport).unwrap()
+    }
+
+    #[test]
+    fn pinned_connection_through_proxy() {
+        let (settings, server) = proxy();
+        let client = client(&["SMuIvYACqojQI8mr9THpHy+ewT4lmMg04Ip6NtG4uwo="], settings);
+        let url = "https://update.clip-os.org/file";
+        let mut res = error_for_status(client.get(url, &[]).unwrap(), url).unwrap();
+        let mut body = String::new();
+        res.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "update");
+
+        let (connect, request) = server.join().unwrap();
+        assert!(connect.starts_with("CONNECT update.clip-os.org:443 HTTP/1.1\r\n"));
+        assert!(connect
+            .to_lowercase()
+            .contains("proxy-authorization: basic dxnlcjpwyxnz\r\n"));
+        let request = request.unwrap();
+        assert!(request.contains("/file HTTP/1.1\r\n"));
+        assert!(request.contains("clipos-version: 5.0.0\r\n"));
+    }
+
+    #[test]
+    fn mismatch_through_proxy() {
+        let (settings, server) = proxy();
+        let client = client(&["diGVwiVYbubAI3RW4hB9xU8e/CH2GnkuvVFZE8zmgzI="], settings);
+        match client.get("https://update.clip-os.org/file", &[]) {
+            Err(Error::Mismatch { presented, .. }) => assert_eq!(
+                presented,
+                "sha256/SMuIvYACqojQI8mr9THpHy+ewT4lmMg04Ip6NtG4uwo="
+            ),
+            r => panic!("unexpected result: {:?}", r.map(|r| r.status())),
+        }
+
+        // No request is sent to a server presenting another key
+        let (_, request) = server.join().unwrap();
+        assert_eq!(request, None);
+    }
+
+    /// Response of the pinned client with the given status
+    fn response(status: u16) -> reqwest::Response {
+        let body: Box<dyn Stream<Item = Chunk, Error = io::Error> + Send> =
+            Box::new(futures::stream::empty());
+        reqwest::Response::from(Response::builder().status(status).body(body).unwrap())
+    }
+
+    #[test]
+    fn only_success_statuses() {
+        for status in &[200, 206] {
+            assert!(error_for_status(response(*status), "url").is_ok());
+        }
+        for status in &[301, 302, 304, 307, 404, 500, 503] {
+            match error_for_status(response(*status), "url") {
+                Err(Error::Status { .. }) => (),
+                _ => panic!("status {} accepted", status),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_pins() {
+        let mut transport = Transport {
+            rootca: Some(include_bytes!("../test/server/rootCA.pem").to_vec()),
+            identity: None,
+            headers: HeaderMap::new(),
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(10),
+            proxy: None,
+            remote: PathBuf::new(),
+        };
+        for pin in &["sha256/", "sha256/not base64", "sha256/AAAA"] {
+            match Client::new(&[String::from(*pin)], &transport) {
+                Err(Error::InvalidPin { .. }) => (),
+                _ => panic!("pin '{}' accepted", pin),
+            }
+        }
+        let pins = [String::from(
+            "sha256/SMuIvYACqojQI8mr9THpHy+ewT4lmMg04Ip6NtG4uwo=",
+        )];
+        assert!(Client::new(&pins, &transport).is_ok());
+        for rootca in vec![None, Some(Vec::new())] {
+            transport.rootca = rootca;
+            match Client::new(&pins, &transport) {
+                Err(Error::InvalidRootCa { .. }) => (),
+                _ => panic!("missing root CA accepted"),
+            }
+        }
+    }
+}
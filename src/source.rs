@@ -0,0 +1,356 @@
+// SPDX-License-Identifier: LGPL-2.1-or-later
+// Copyright © 2019 ANSSI. All rights reserved.
+
+//! Sources for update metadata and payloads.
+//!
+//! Mirrors are either HTTPS servers or local directories (`file://` URLs,
+//! e.g. removable media on air-gapped sites) with the same `webroot` layout.
+//! Both may resume partial downloads. HTTPS servers are reached with the
+//! pinned client of the `pin` module if server public keys are pinned.
+
+use reqwest::header::{CONTENT_RANGE, ETAG, IF_RANGE, RANGE};
+use reqwest::{Client, StatusCode, Url};
+use snafu::{ResultExt, Snafu};
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use crate::error::{Classify, ErrorClass};
+use crate::pin;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Invalid URL '{}': {}", url, source))]
+    InvalidUrl {
+        url: String,
+        source: reqwest::UrlError,
+    },
+    #[snafu(display("Invalid local path in URL '{}'", url))]
+    InvalidPath { url: String },
+    #[snafu(display("No mirror configured"))]
+    NoMirror,
+    #[snafu(display("HTTP request failed: {}", source))]
+    HTTP { source: reqwest::Error },
+    #[snafu(display("Could not open '{}': {}", filename.display(), source))]
+    Open {
+        filename: PathBuf,
+        source: io::Error,
+    },
+    #[snafu(display("{}", source))]
+    Pin { source: pin::Error },
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Error {
+        Error::HTTP { source: err }
+    }
+}
+
+impl From<pin::Error> for Error {
+    fn from(err: pin::Error) -> Error {
+        Error::Pin { source: err }
+    }
+}
+
+impl Classify for Error {
+    fn class(&self) -> ErrorClass {
+        match self {
+            Error::InvalidUrl { .. } | Error::InvalidPath { .. } | Error::NoMirror => {
+                ErrorClass::Config
+            }
+            Error::HTTP { .. } | Error::Open { .. } => ErrorClass::Network,
+            Error::Pin { source } => source.class(),
+        }
+    }
+
+    fn transient(&self) -> bool {
+        match self {
+            // Connection errors, timeouts and server errors
+            Error::HTTP { source } => source.status().map_or(true, |s| s.is_server_error()),
+            Error::Pin { source } => source.transient(),
+            _ => false,
+        }
+    }
+}
+
+impl Error {
+    /// Whether the requested file does not exist on the source
+    pub fn not_found(&self) -> bool {
+        match self {
+            Error::HTTP { source } => source.status() == Some(StatusCode::NOT_FOUND),
+            Error::Open { source, .. } => source.kind() == io::ErrorKind::NotFound,
+            _ => false,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Content being downloaded from a source
+pub struct Response {
+    reader: Box<dyn Read>,
+    /// Size of the content left to read, if known
+    pub length: Option<u64>,
+    /// Offset in the file at which the content starts, if a partial download
+    /// has been resumed
+    pub offset: Option<u64>,
+    /// Identifier of this version of the file, to safely resume downloads
+    pub etag: Option<String>,
+}
+
+impl Read for Response {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// Where update metadata and payloads are downloaded from
+pub trait Source {
+    /// Open url for download. If resume is set, only get the part of the
+    /// file after the given offset, provided that the file still has the
+    /// given ETag. Otherwise, or if this is not possible, get the whole file.
+    fn open(&self, url: &Url, resume: Option<&(u64, String)>) -> Result<Response>;
+}
+
+/// Parse a mirror URL
+pub fn parse_url(url: &str) -> Result<Url> {
+    Url::parse(url).context(InvalidUrl { url })
+}
+
+/// HTTPS server
+pub struct Http<'a> {
+    pub client: &'a Client,
+}
+
+impl<'a> Source for Http<'a> {
+    fn open(&self, url: &Url, resume: Option<&(u64, String)>) -> Result<Response> {
+        if let Some((offset, etag)) = resume {
+            let res = self
+                .client
+                .get(url.clone())
+                .header(RANGE, format!("bytes={}-", offset))
+                .header(IF_RANGE, etag.as_str())
+                .send()?;
+            if let Some(res) = partial(res, *offset, etag) {
+                return Ok(res);
+            }
+            // The server can not resume this download, start over
+        }
+
+        let res = self.client.get(url.clone()).send()?.error_for_status()?;
+        Ok(response(res, None))
+    }
+}
+
+/// HTTPS server with pinned public keys
+pub struct Pinned<'a> {
+    pub client: &'a pin::Client,
+}
+
+impl<'a> Source for Pinned<'a> {
+    fn open(&self, url: &Url, resume: Option<&(u64, String)>) -> Result<Response> {
+        if let Some((offset, etag)) = resume {
+            let range = format!("bytes={}-", offset);
+            let res = self
+                .client
+                .get(url.as_str(), &[(RANGE, &range), (IF_RANGE, etag)])?;
+            if let Some(res) = partial(res, *offset, etag) {
+                return Ok(res);
+            }
+            // The server can not resume this download, start over
+        }
+
+        let res = self.client.get(url.as_str(), &[])?;
+        Ok(response(pin::error_for_status(res, url.as_str())?, None))
+    }
+}
+
+/// Use the answer of the server to a range request if it holds either the
+/// remaining part of the same file or the whole file
+fn partial(res: reqwest::Response, offset: u64, etag: &str) -> Option<Response> {
+    if resumed(&res, offset, etag) {
+        return Some(response(res, Some(offset)));
+    }
+    if res.status() == StatusCode::OK {
+        return Some(response(res, None));
+    }
+    None
+}
+
+fn response(res: reqwest::Response, offset: Option<u64>) -> Response {
+    Response {
+        length: res.content_length(),
+        etag: res
+            .headers()
+            .get(ETAG)
+            .and_then(|t| t.to_str().ok())
+            .map(String::from),
+        offset,
+        reader: Box::new(res),
+    }
+}
+
+/// Tell if the server answered a range request with the remaining part of the
+/// same file
+fn resumed(res: &reqwest::Response, offset: u64, etag: &str) -> bool {
+    if res.status() != StatusCode::PARTIAL_CONTENT {
+        debug!("Server did not resume download, status: {}", res.status());
+        return false;
+    }
+    let header = |name| {
+        res.headers()
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("")
+    };
+    let range = header(CONTENT_RANGE);
+    let tag = header(ETAG);
+    if tag != etag || !range.starts_with(&format!("bytes {}-", offset)) {
+        warn!(
+            "Server sent a different file or range (ETag: '{}', range: '{}')",
+            tag, range
+        );
+        return false;
+    }
+    true
+}
+
+/// Local directory
+pub struct Local;
+
+impl Source for Local {
+    fn open(&self, url: &Url, resume: Option<&(u64, String)>) -> Result<Response> {
+        let filename = url.to_file_path().map_err(|()| Error::InvalidPath {
+            url: url.to_string(),
+        })?;
+        let mut file = File::open(&filename).context(Open {
+            filename: &filename,
+        })?;
+        let metadata = file.metadata().context(Open {
+            filename: &filename,
+        })?;
+
+        // Files are identified by their size and modification time
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        let etag = format!("{}-{}", metadata.len(), mtime);
+
+        let offset = match resume {
+            Some((o, t)) if *t == etag && *o <= metadata.len() => {
+                file.seek(SeekFrom::Start(*o)).context(Open {
+                    filename: &filename,
+                })?;
+                Some(*o)
+            }
+            _ => None,
+        };
+
+        Ok(Response {
+            length: Some(metadata.len() - offset.unwrap_or(0)),
+            offset,
+            etag: Some(etag),
+            reader: Box::new(file),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_dir;
+    use reqwest::header::HeaderName;
+    use std::fs;
+
+    /// Answer of a server with the given status and headers
+    fn response(status: u16, headers: &[(HeaderName, &str)]) -> reqwest::Response {
+        let mut builder = hyper::Response::builder();
+        builder.status(status);
+        for (name, value) in headers {
+            builder.header(name, *value);
+        }
+        reqwest::Response::from(builder.body(Vec::new()).unwrap())
+    }
+
+    #[test]
+    fn resumed_download() {
+        let res = response(206, &[(ETAG, "\"v1\""), (CONTENT_RANGE, "bytes 10-19/20")]);
+        assert!(resumed(&res, 10, "\"v1\""));
+        let res = partial(res, 10, "\"v1\"").unwrap();
+        assert_eq!(res.offset, Some(10));
+        assert_eq!(res.etag, Some(String::from("\"v1\"")));
+    }
+
+    #[test]
+    fn whole_file_instead_of_range() {
+        let res = response(200, &[(ETAG, "\"v2\"")]);
+        assert!(!resumed(&res, 10, "\"v1\""));
+        let res = partial(res, 10, "\"v1\"").unwrap();
+        assert_eq!(res.offset, None);
+        assert_eq!(res.etag, Some(String::from("\"v2\"")));
+    }
+
+    #[test]
+    fn other_file_or_range() {
+        // The file has changed since the download started
+        let res = response(206, &[(ETAG, "\"v2\""), (CONTENT_RANGE, "bytes 10-19/20")]);
+        assert!(!resumed(&res, 10, "\"v1\""));
+        assert!(partial(res, 10, "\"v1\"").is_none());
+
+        // Not the requested range
+        let res = response(206, &[(ETAG, "\"v1\""), (CONTENT_RANGE, "bytes 0-19/20")]);
+        assert!(!resumed(&res, 10, "\"v1\""));
+        assert!(partial(res, 10, "\"v1\"").is_none());
+        let res = response(
+            206,
+            &[(ETAG, "\"v1\""), (CONTENT_RANGE, "bytes 100-119/120")],
+        );
+        assert!(!resumed(&res, 10, "\"v1\""));
+
+        // Missing headers
+        assert!(!resumed(&response(206, &[]), 10, "\"v1\""));
+    }
+
+    #[test]
+    fn range_refused() {
+        for status in &[304, 412, 416] {
+            let res = response(*status, &[(ETAG, "\"v1\"")]);
+            assert!(partial(res, 10, "\"v1\"").is_none());
+        }
+    }
+
+    #[test]
+    fn local_resume() {
+        let dir = test_dir("source-local");
+        let filename = format!("{}/payload", dir);
+        fs::write(&filename, "0123456789").unwrap();
+        let url = Url::from_file_path(&filename).unwrap();
+
+        let res = Local.open(&url, None).unwrap();
+        assert_eq!((res.offset, res.length), (None, Some(10)));
+        let etag = res.etag.clone().unwrap();
+
+        let mut res = Local.open(&url, Some(&(4, etag.clone()))).unwrap();
+        assert_eq!((res.offset, res.length), (Some(4), Some(6)));
+        let mut rest = String::new();
+        res.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "456789");
+
+        // Other file, or offset beyond its end: start over
+        let res = Local.open(&url, Some(&(4, String::from("other")))).unwrap();
+        assert_eq!(res.offset, None);
+        let res = Local.open(&url, Some(&(11, etag))).unwrap();
+        assert_eq!(res.offset, None);
+
+        let missing = Url::from_file_path(format!("{}/missing", dir)).unwrap();
+        match Local.open(&missing, None) {
+            Err(ref e) if e.not_found() => (),
+            _ => panic!("missing file found"),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: LGPL-2.1-or-later
+// Copyright © 2019 ANSSI. All rights reserved.
+
+//! State files kept between runs: issue time of the last accepted manifest,
+//! revoked keys, anti-rollback floor, etc.
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/// Replace the content of a state file atomically not to lose the previous
+/// state on failure. The content is written to a temporary file that is
+/// synced to disk and renamed, then the folder is synced so that the rename
+/// itself survives a power loss.
+pub fn write(filename: &str, content: &[u8]) -> io::Result<()> {
+    let tmp = format!("{}.tmp", filename);
+    let mut file = File::create(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    fs::rename(&tmp, filename)?;
+
+    let dir = match Path::new(filename).parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Create an empty folder for the state files of a test
+#[cfg(test)]
+pub fn test_dir(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("updater-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_replaces_content() {
+        let dir = test_dir("state-write");
+        let file = format!("{}/state", dir);
+        write(&file, b"first\n").unwrap();
+        write(&file, b"second\n").unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"second\n");
+        assert!(!Path::new(&format!("{}.tmp", file)).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_to_missing_folder() {
+        let dir = test_dir("state-missing");
+        assert!(write(&format!("{}/missing/state", dir), b"state\n").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
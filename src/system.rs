@@ -1,21 +1,34 @@
 // SPDX-License-Identifier: LGPL-2.1-or-later
 // Copyright © 2019 ANSSI. All rights reserved.
 
+use blake2::{Blake2b, Digest};
 use libmount::mountinfo::{MountPoint, Parser};
-use minisign::PublicKey;
 use minisign::SignatureBox;
-use reqwest::Client;
+use nix::sys::statvfs::statvfs;
+use ring::digest;
 use semver::Version;
-use snafu::{ResultExt, Snafu};
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::config::Remote;
+use crate::config::{Mirror, Remote};
+use crate::error::{Classify, ErrorClass};
+use crate::floor;
+use crate::keys;
+use crate::keys::{Keys, TrustedKey};
+use crate::manifest;
+use crate::manifest::ManifestPackage;
+use crate::source::Response;
 use lvm;
 
 #[derive(Debug, Snafu)]
@@ -57,6 +70,19 @@ pub enum Error {
         filename: PathBuf,
         source: minisign::PError,
     },
+    #[snafu(display(
+        "File '{}' has {} valid signatures from distinct trusted keys, {} required",
+        filename.display(),
+        valid,
+        threshold
+    ))]
+    NotEnoughSignatures {
+        filename: PathBuf,
+        valid: usize,
+        threshold: usize,
+    },
+    #[snafu(display("Signature '{}' is not prehashed, payloads must be signed with 'minisign -H'", filename.display()))]
+    NotPrehashed { filename: PathBuf },
     #[snafu(display("Invalid trusted comment for file '{}': {}", filename.display(), source))]
     InvalidTrustedComment {
         filename: PathBuf,
@@ -72,8 +98,31 @@ pub enum Error {
         expected: semver::Version,
         comment: semver::Version,
     },
-    #[snafu(display("HTTP request failed: {}", source))]
-    HTTP { source: reqwest::Error },
+    #[snafu(display("{}", source))]
+    Source { source: crate::source::Error },
+    #[snafu(display("Response from '{}' is larger than {} bytes", url, limit))]
+    TooLarge { url: String, limit: u64 },
+    #[snafu(display("Could not download '{}': {}", url, source))]
+    Download { url: String, source: io::Error },
+    #[snafu(display("{}", source))]
+    Manifest { source: manifest::Error },
+    #[snafu(display("{}", source))]
+    Floor { source: floor::Error },
+    #[snafu(display(
+        "File '{}' does not match the manifest: expected {} bytes with SHA-256 '{}', got {} bytes with SHA-256 '{}'",
+        filename.display(),
+        expected_size,
+        expected_hash,
+        size,
+        hash
+    ))]
+    PayloadMismatch {
+        filename: PathBuf,
+        expected_size: u64,
+        expected_hash: String,
+        size: u64,
+        hash: String,
+    },
 
     #[snafu(display("LVM command returned an error: {}", source))]
     Lvm { source: lvm::Error },
@@ -98,13 +147,153 @@ pub enum Error {
     },
     #[snafu(display("Could not found destination VG '{}'", vg))]
     VgNotFound { vg: String },
+
+    #[snafu(display(
+        "Could not find an older installed version to rollback to from '{}'",
+        version
+    ))]
+    NoRollbackTarget { version: semver::Version },
+    #[snafu(display("Can not rollback to '{}': {}", lv, reason))]
+    NotIntact { lv: String, reason: String },
+
+    #[snafu(display("Could not get free space for '{}': {}", path.display(), source))]
+    Statvfs { path: PathBuf, source: nix::Error },
+    #[snafu(display("Could not find filesystem holding '{}': {}", path.display(), source))]
+    Filesystem { path: PathBuf, source: io::Error },
+    #[snafu(display("Invalid size '{}'", size))]
+    InvalidSize { size: String },
+    #[snafu(display("Total size needed in {} overflows", target))]
+    SizeOverflow { target: String },
+    #[snafu(display(
+        "Not enough space in cache folder '{}': {} bytes needed, {} available",
+        path.display(),
+        needed,
+        available
+    ))]
+    NoCacheSpace {
+        path: PathBuf,
+        needed: u64,
+        available: u64,
+    },
+    #[snafu(display(
+        "Not enough free space in VG '{}': {} bytes needed, {} available",
+        vg,
+        needed,
+        available
+    ))]
+    NoVgSpace {
+        vg: String,
+        needed: u64,
+        available: u64,
+    },
+    #[snafu(display(
+        "LV '{}' is too small: {} bytes needed, {} available",
+        lv,
+        needed,
+        available
+    ))]
+    LvTooSmall {
+        lv: String,
+        needed: u64,
+        available: u64,
+    },
+    #[snafu(display(
+        "Not enough space on ESP for '{}': {} bytes needed, {} available",
+        path.display(),
+        needed,
+        available
+    ))]
+    NoEspSpace {
+        path: PathBuf,
+        needed: u64,
+        available: u64,
+    },
+    #[snafu(display(
+        "Not enough space for '{}': {} bytes needed, {} available",
+        path.display(),
+        needed,
+        available
+    ))]
+    NoFileSpace {
+        path: PathBuf,
+        needed: u64,
+        available: u64,
+    },
+
+    #[snafu(display("Failed to call 'bootctl': {}", source))]
+    Bootctl { source: io::Error },
+    #[snafu(display("'bootctl' could not set '{}' as default boot entry", entry))]
+    BootctlFailed { entry: String },
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
-impl From<reqwest::Error> for Error {
-    fn from(err: reqwest::Error) -> Error {
-        Error::HTTP { source: err }
+impl Classify for Error {
+    fn class(&self) -> ErrorClass {
+        match self {
+            Error::DecodeSignature { .. }
+            | Error::InvalidSignature { .. }
+            | Error::NotPrehashed { .. }
+            | Error::InvalidTrustedComment { .. }
+            | Error::InvalidVersion { .. }
+            | Error::PayloadMismatch { .. }
+            | Error::NotEnoughSignatures { .. } => ErrorClass::Signature,
+            Error::Manifest { source } => source.class(),
+            Error::Floor { source } => source.class(),
+            Error::VersionMismatch { .. } => ErrorClass::VersionMismatch,
+            Error::TooLarge { .. } | Error::Download { .. } => ErrorClass::Network,
+            Error::Source { source } => source.class(),
+            Error::Lvm { .. }
+            | Error::VgNotFound { .. }
+            | Error::NoRollbackTarget { .. }
+            | Error::NotIntact { .. } => ErrorClass::Lvm,
+            Error::NoCacheSpace { .. }
+            | Error::NoVgSpace { .. }
+            | Error::LvTooSmall { .. }
+            | Error::NoEspSpace { .. }
+            | Error::NoFileSpace { .. }
+            | Error::SizeOverflow { .. } => ErrorClass::DiskSpace,
+            Error::InvalidSize { .. } => ErrorClass::Config,
+            Error::Io { .. }
+            | Error::Content { .. }
+            | Error::Copy { .. }
+            | Error::Rename { .. }
+            | Error::Remove { .. }
+            | Error::Sync { .. }
+            | Error::Mountinfo { .. }
+            | Error::ReadDir { .. }
+            | Error::DirEntry { .. }
+            | Error::Statvfs { .. }
+            | Error::Filesystem { .. }
+            | Error::Bootctl { .. }
+            | Error::BootctlFailed { .. } => ErrorClass::Io,
+        }
+    }
+
+    fn transient(&self) -> bool {
+        match self {
+            Error::Download { .. } => true,
+            Error::Source { source } => source.transient(),
+            _ => false,
+        }
+    }
+}
+
+impl From<crate::source::Error> for Error {
+    fn from(err: crate::source::Error) -> Error {
+        Error::Source { source: err }
+    }
+}
+
+impl From<manifest::Error> for Error {
+    fn from(err: manifest::Error) -> Error {
+        Error::Manifest { source: err }
+    }
+}
+
+impl From<floor::Error> for Error {
+    fn from(err: floor::Error) -> Error {
+        Error::Floor { source: err }
     }
 }
 
@@ -114,25 +303,98 @@ impl From<lvm::Error> for Error {
     }
 }
 
+/// Parse a size as given to `lvcreate -L` ('500M', '1.5G', etc.) into bytes.
+/// Units are powers of 1024 and default to MiB. Decimal sizes are rounded up
+/// to the next byte, as lvcreate rounds them up to the next extent.
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        None => (size, "m"),
+        Some(i) => size.split_at(i),
+    };
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "b" => 1,
+        "s" => 512,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        "t" => 1 << 40,
+        "p" => 1 << 50,
+        "e" => 1 << 60,
+        _ => return None,
+    };
+    let (integer, fraction) = match number.find('.') {
+        None => (number, ""),
+        Some(i) => (&number[..i], &number[i + 1..]),
+    };
+    if integer.is_empty() && fraction.is_empty() {
+        return None;
+    }
+    let integer = match integer {
+        "" => 0,
+        i => i.parse::<u64>().ok()?,
+    };
+    // At most 18 decimal places so that the computation can not overflow
+    let fraction = match fraction {
+        "" => 0,
+        f if f.len() > 18 || !f.bytes().all(|b| b.is_ascii_digit()) => return None,
+        f => {
+            let scale = 10u128.pow(f.len() as u32);
+            let bytes = f.parse::<u128>().ok()? * u128::from(multiplier);
+            ((bytes + scale - 1) / scale) as u64
+        }
+    };
+    integer.checked_mul(multiplier)?.checked_add(fraction)
+}
+
+/// Marker file created once an update has been installed and is waiting for
+/// a reboot
+pub const UPDATE_READY: &str = "/run/update_ready";
+
+/// Paths to the EFI binaries replaced by a bootloader package, relative to
+/// the EFI System Partition
+const BOOTLOADER_PATHS: [&str; 2] = ["EFI/systemd/systemd-bootx64.efi", "EFI/BOOT/BOOTX64.EFI"];
+
+/// EFI variable set by systemd-boot when a default boot entry is configured
+const LOADER_ENTRY_DEFAULT: &str =
+    "/sys/firmware/efi/efivars/LoaderEntryDefault-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
+
 /// Meta structure to represent the current system state and ensure
 /// that updates are installed in the correct order.
 pub struct System {
     pub os_name: String,
     pub version: Version,
 
-    core: Package,
-    efiboot: Package,
+    /// Packages sorted by installation order
+    packages: Vec<Package>,
 
-    pubkey: PublicKey,
+    keys: Keys,
 
     download_cache: String,
+
+    /// Folder for the state kept between runs, which must not be lost with
+    /// the download cache
+    state: String,
 }
 
-/// The kind of package currently supported
-#[derive(Debug, PartialEq)]
+/// The installation backends currently supported for packages
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
 pub enum Kind {
-    Core,
-    Efiboot,
+    /// Image copied at block level to a `<name>_<version>` Logical Volume in
+    /// the destination Volume Group. The LV not used by the current version is
+    /// reused for the update.
+    Lv,
+    /// EFI binary installed as `<os_name>-<version>.efi` in the destination
+    /// folder of the EFI System Partition, used as boot entry. All other files
+    /// in this folder are removed before any LV is overwritten.
+    Esp,
+    /// Plain file atomically replaced at the destination path
+    File,
+    /// systemd-boot binary replacing both the systemd-boot and the fallback
+    /// EFI binaries on the EFI System Partition mounted at the destination.
+    /// The previous binaries are kept as backups. Always installed last.
+    Bootloader,
 }
 
 /// Represent a package (core, efiboot, etc.) to install on the system
@@ -142,42 +404,173 @@ pub struct Package {
     name: String,
     destination: String,
     size: Option<String>,
+    order: u32,
+    max_size: Option<u64>,
+}
+
+/// Summary of the update state of the system, as reported by `status`
+#[derive(Serialize, Debug)]
+pub struct Status {
+    pub os_name: String,
+    pub version: String,
+    pub lvs: Vec<LvStatus>,
+    pub efiboot: Vec<String>,
+    pub cache: Vec<CacheStatus>,
+    pub update_ready: bool,
+}
+
+/// An LV holding a versioned package image
+#[derive(Serialize, Debug)]
+pub struct LvStatus {
+    pub name: String,
+    pub version: String,
+    pub current: bool,
+}
+
+/// A package payload found in the download cache
+#[derive(Serialize, Debug)]
+pub struct CacheStatus {
+    pub name: String,
+    pub path: String,
+    pub size: u64,
+    pub signature: bool,
+}
+
+/// Actions needed to install an update, as selected by `System::plan`
+pub struct InstallPlan {
+    pub version: Version,
+    /// Sanctioned downgrade below the anti-rollback floor
+    pub downgrade: bool,
+    /// Boot entries removed before overwriting any LV
+    pub remove: Vec<PathBuf>,
+    /// Installation steps, in package installation order
+    pub steps: Vec<Step>,
+}
+
+/// Actions planned to rollback to a previously installed version
+pub struct RollbackPlan {
+    pub version: Version,
+    /// LVs of the version, verified before anything is installed
+    pub lvs: Vec<String>,
+    /// Files reinstalled from their kept copies, in package order
+    pub steps: Vec<RollbackStep>,
+    /// Boot entry made the default one, if any
+    pub entry: Option<String>,
+}
+
+/// Reinstallation of a single package from its kept copy
+pub struct RollbackStep {
+    pub package: String,
+    pub kind: Kind,
+    pub kept: String,
+    /// Whether the kept copy is not valid anymore and is downloaded again
+    pub download: bool,
+    pub dests: Vec<String>,
+}
+
+/// Installation of a single package
+pub struct Step {
+    pub package: String,
+    pub kind: Kind,
+    pub image: String,
+    pub size: u64,
+    pub action: Action,
+}
+
+/// How a package image is installed
+pub enum Action {
+    /// Copy the image over an existing LV of `size` bytes, renamed to `name`
+    /// if needed and first extended to `grow` bytes if the image does not fit
+    OverwriteLv {
+        lv: lvm::Lv,
+        name: String,
+        size: u64,
+        grow: Option<u64>,
+    },
+    /// Create a new LV and copy the image into it
+    CreateLv {
+        vg: lvm::Vg,
+        name: String,
+        size: String,
+    },
+    /// Copy the image under a temporary name and rename it to `dest`
+    CopyFile { dest: String },
+    /// Backup the current bootloader binaries and replace them with the image
+    ReplaceBootloader { dests: Vec<String> },
 }
 
 impl Package {
-    pub fn new(kind: Kind, destination: &str, size: Option<String>) -> Package {
-        let name = match kind {
-            Kind::Core => String::from("core"),
-            Kind::Efiboot => String::from("efiboot"),
-        };
+    pub fn new(
+        name: &str,
+        kind: Kind,
+        destination: &str,
+        size: Option<String>,
+        order: u32,
+        max_size: Option<u64>,
+    ) -> Package {
         Package {
             kind,
-            name,
+            name: String::from(name),
             destination: String::from(destination),
             size,
+            order,
+            max_size,
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    pub fn destination(&self) -> &str {
+        &self.destination
+    }
+
+    pub fn order(&self) -> u32 {
+        self.order
+    }
+
+    pub fn size(&self) -> Option<&str> {
+        self.size.as_ref().map(String::as_str)
+    }
 }
 
 impl System {
     pub fn new(
         os_name: String,
-        core: Package,
-        efiboot: Package,
+        mut packages: Vec<Package>,
         version: Version,
-        pubkey: PublicKey,
+        keys: Keys,
         download_cache: String,
+        state: String,
     ) -> System {
+        // Stable sort: packages with the same order keep their relative order.
+        // The bootloader is only replaced once everything else is installed.
+        packages.sort_by_key(|p| (p.kind == Kind::Bootloader, p.order));
         System {
             os_name,
-            core,
-            efiboot,
+            packages,
             version,
-            pubkey,
+            keys,
             download_cache,
+            state,
         }
     }
 
+    /// Trusted keys used to verify update metadata and payloads
+    pub(crate) fn keys(&self) -> &Keys {
+        &self.keys
+    }
+
+    /// Packages handled by a given backend, in installation order
+    fn packages(&self, kind: Kind) -> impl Iterator<Item = &Package> {
+        self.packages.iter().filter(move |p| p.kind == kind)
+    }
+
     /// Generate file name for package as stored in cache folder
     pub fn cache(&self, pkg: &Package) -> String {
         format!("{}/{}-{}", self.download_cache, &self.os_name, pkg.name)
@@ -187,14 +580,87 @@ impl System {
         format!("{}/{}-{}.sig", self.download_cache, &self.os_name, pkg.name)
     }
 
+    /// Generate file name for the state file recording the issue time of the
+    /// last accepted manifest
+    pub fn manifest_state(&self) -> String {
+        format!("{}/{}-manifest.issued", self.state, &self.os_name)
+    }
+    /// Generate file name for the state file recording revoked key IDs
+    pub fn revoked_state(&self) -> String {
+        format!("{}/{}-revoked", self.state, &self.os_name)
+    }
+    /// Generate file name for the state file recording the highest version
+    /// ever installed
+    pub fn floor_state(&self) -> String {
+        format!("{}/{}-floor", self.state, &self.os_name)
+    }
+    /// Generate file name for the state file recording the version the system
+    /// has been rolled back from
+    pub fn rollback_state(&self) -> String {
+        format!("{}/{}-rollback", self.state, &self.os_name)
+    }
+    /// Generate file name for the downgrade override as stored in state
+    /// folder
+    pub fn downgrade_override(&self) -> String {
+        format!("{}/{}-downgrade", self.state, &self.os_name)
+    }
+
+    /// Generate file names for the signatures of a file, given the name of
+    /// its first signature: `<file>.sig`, then `<file>.sig.<n>` for additional
+    /// signatures made with other keys
+    fn sig_files(&self, sig: &str) -> Vec<String> {
+        let mut files = vec![String::from(sig)];
+        files.extend((1..=self.keys.count()).map(|n| format!("{}.{}", sig, n)));
+        files
+    }
+
+    /// Generate file names for the existing signatures of a file
+    fn existing_sigs(&self, sig: &str) -> Vec<String> {
+        self.sig_files(sig)
+            .into_iter()
+            .filter(|s| Path::new(s).exists())
+            .collect()
+    }
+
+    /// Generate file name for the copy of an installed package kept in cache
+    /// folder for rollbacks
+    pub fn kept(&self, pkg: &Package, v: &Version) -> String {
+        format!(
+            "{}/{}-{}-{}",
+            self.download_cache, &self.os_name, pkg.name, v
+        )
+    }
+    /// Generate file name for the signature of a kept package
+    pub fn kept_sig(&self, pkg: &Package, v: &Version) -> String {
+        format!(
+            "{}/{}-{}-{}.sig",
+            self.download_cache, &self.os_name, pkg.name, v
+        )
+    }
+    /// Generate file name for the size of the image installed to the LV of
+    /// an LV package, kept along with its signatures
+    pub fn kept_size(&self, pkg: &Package, v: &Version) -> String {
+        format!("{}.size", self.kept(pkg, v))
+    }
+
     /// Generate final installation destination file name for package
     pub fn dest(&self, pkg: &Package, v: &Version) -> String {
         match pkg.kind {
-            Kind::Efiboot => format!("{}/{}-{}.efi", pkg.destination, &self.os_name, v),
-            Kind::Core => format!("/dev/{}/{}_{}", pkg.destination, pkg.name, v),
+            Kind::Esp => format!("{}/{}-{}.efi", pkg.destination, &self.os_name, v),
+            Kind::Lv => format!("/dev/{}/{}_{}", pkg.destination, pkg.name, v),
+            Kind::File => pkg.destination.clone(),
+            Kind::Bootloader => format!("{}/{}", pkg.destination, BOOTLOADER_PATHS[0]),
         }
     }
 
+    /// Generate all destination file names for a bootloader package
+    fn bootloader_dests(pkg: &Package) -> Vec<String> {
+        BOOTLOADER_PATHS
+            .iter()
+            .map(|p| format!("{}/{}", pkg.destination, p))
+            .collect()
+    }
+
     /// Generate URL to download package with given version
     pub fn url(&self, pkg: &Package, url: &str, v: &Version) -> String {
         format!("{}/{}/{}-{}", url, v, &self.os_name, pkg.name)
@@ -205,70 +671,108 @@ impl System {
     }
 
     /// Update steps:
-    /// 1. Download and validate efiboot
-    /// 2. Download and validate core
-    /// 3. Install core
-    /// 4. Install efiboot
-    pub fn update(&self, remote: Remote, version: Version) -> Result<()> {
-        info!("Starting update to version '{}'", version);
+    /// 1. Download and validate all packages
+    /// 2. Remove boot entries for the versions about to be overwritten
+    /// 3. Install all packages in order
+    pub fn update(&self, remote: &Remote, manifest: &manifest::Manifest) -> Result<()> {
+        info!("Starting update to version '{}'", manifest.version);
 
-        self.download(&self.efiboot, &remote, &version)?;
-        self.download(&self.core, &remote, &version)?;
-
-        self.install(&version)
+        self.download(remote, manifest)?;
+        self.install(&manifest.version)
     }
 
-    /// Download given package with corresponding version from remote
-    fn download(&self, pkg: &Package, r: &Remote, v: &Version) -> Result<()> {
-        let file_url = &self.url(pkg, &r.dist_url, v);
-        let file_dst = &self.cache(pkg);
-        let sig_url = &self.url_sig(pkg, &r.dist_url, v);
-        let sig_dst = &self.cache_sig(pkg);
-
-        // Have we already downloaded a valid file?
-        match self.validate(file_dst, sig_dst, v) {
-            Err(_e) => debug!("invalid or incomplete precedent download"),
-            Ok(()) => {
-                info!("Reusing sucessfully downloaded and verified '{}'", file_dst);
-                return Ok(());
-            }
+    /// Download and validate all packages described by the manifest into the
+    /// cache folder, without installing them
+    pub fn download(&self, remote: &Remote, manifest: &manifest::Manifest) -> Result<()> {
+        // Make sure that all payloads fit in the cache folder before writing
+        // any of them. Files already in the cache are either reused, resumed
+        // or replaced.
+        let mut downloads = Vec::new();
+        let mut needed = 0;
+        let mut existing = Vec::new();
+        for pkg in &self.packages {
+            let expected = match manifest.package(&pkg.name) {
+                // The bootloader is not updated with every release
+                Err(_) if pkg.kind == Kind::Bootloader => {
+                    info!("No update for bootloader '{}'", pkg.name);
+                    self.remove_cached(pkg);
+                    continue;
+                }
+                r => r?,
+            };
+            needed = needed.checked_add(expected.size).context(SizeOverflow {
+                target: format!("cache folder '{}'", self.download_cache),
+            })?;
+            existing.push(self.cache(pkg));
+            downloads.push((pkg, expected));
         }
+        self.check_cache_space(needed, &existing)?;
 
-        // Download requested file & its signature
-        System::download_file(&file_url, &file_dst, r)?;
-        System::download_file(&sig_url, &sig_dst, r)?;
-
-        match self.validate(file_dst, sig_dst, v) {
-            Err(e) => return Err(e),
-            Ok(()) => info!("Sucessfully downloaded and verified '{}'", file_dst),
+        for (pkg, expected) in downloads {
+            self.download_package(pkg, remote, &manifest.version, expected)?;
         }
         Ok(())
     }
 
-    /// Download URL src to file dst using remote information
-    fn download_file(src: &str, dst: &str, r: &Remote) -> Result<()> {
-        debug!("Downloading '{}' to '{}'", src, dst);
-
-        // Setup reqwest Client
-        let client = Client::builder()
-            .add_root_certificate(r.rootca.clone())
-            .default_headers(r.headers.clone())
-            .build()?;
-
-        let mut res = client.get(src).send()?;
-        let mut buf = File::create(dst).context(Io { filename: &dst })?;
-
-        res.copy_to(&mut buf)?;
+    /// Make sure that the cache folder has room for needed bytes, in place of
+    /// the given existing files
+    fn check_cache_space(&self, needed: u64, existing: &[String]) -> Result<()> {
+        let dir = Path::new(&self.download_cache);
+        let freed: u64 = existing
+            .iter()
+            .filter_map(|f| fs::metadata(f).ok())
+            .map(|m| m.len())
+            .sum();
+        let available = System::free_space(dir)? + freed;
+        if available < needed {
+            return Err(Error::NoCacheSpace {
+                path: dir.to_path_buf(),
+                needed,
+                available,
+            });
+        }
         Ok(())
     }
 
-    /// Verify file using signature from sig, validating that the version match
-    fn validate(&self, file: &str, sig: &str, v: &Version) -> Result<()> {
-        let f = File::open(&file).context(Io { filename: file })?;
+    /// Look for update payloads previously downloaded to the cache folder and
+    /// return their version if all of them are valid and newer than the
+    /// currently running version
+    ///
+    /// # Example
+    ///
+    /// Install an update downloaded by a previous run:
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let (system, _remote) = updater::parse(
+    /// #     "/usr/lib/updater".into(),
+    /// #     "/etc/updater".into(),
+    /// #     "/var/lib/updater".into(),
+    /// #     "/var/lib/updater-state".into(),
+    /// # )?;
+    /// if let Some(version) = system.staged()? {
+    ///     println!("{}", system.plan(&version)?);
+    ///     system.install(&version)?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn staged(&self) -> Result<Option<Version>> {
+        // The first package signature trusted comment gives us the candidate
+        // version
+        let first = match self.packages.first() {
+            None => return Ok(None),
+            Some(p) => p,
+        };
+        let sigs = self.existing_sigs(&self.cache_sig(first));
+        let sig = match sigs.first() {
+            None => {
+                debug!("No signature found in cache for '{}'", self.cache(first));
+                return Ok(None);
+            }
+            Some(s) => s,
+        };
         let s = SignatureBox::from_file(sig).context(DecodeSignature { filename: sig })?;
-        minisign::verify(&self.pubkey, &s, f, true, false)
-            .context(InvalidSignature { filename: sig })?;
-
         let trusted_comment = s
             .trusted_comment()
             .context(InvalidTrustedComment { filename: sig })?;
@@ -276,148 +780,183 @@ impl System {
             version: trusted_comment,
         })?;
 
-        if version != *v {
-            return Err(Error::VersionMismatch {
-                expected: (*v).clone(),
-                comment: version,
-            });
+        // Downloads in progress are marked with a `.etag` file, holding the
+        // ETag of the file to resume it later, if any. Interrupted downloads
+        // are kept and are not ready to be installed.
+        for pkg in self.packages.iter().filter(|p| !self.skipped(p)) {
+            let cache = &self.cache(pkg);
+            if !Path::new(cache).exists() || Path::new(&format!("{}.etag", cache)).exists() {
+                info!("Download of '{}' is not complete", cache);
+                return Ok(None);
+            }
         }
 
-        Ok(())
-    }
+        // Validate all packages against this version
+        for pkg in self.packages.iter().filter(|p| !self.skipped(p)) {
+            self.validate(&self.cache(pkg), &self.cache_sig(pkg), &version, None)?;
+        }
 
-    /// Install the system update
-    fn install(&self, version: &Version) -> Result<()> {
-        let core = &self.core;
-        let efiboot = &self.efiboot;
+        if version == self.version || (version < self.version && !self.sanctioned(&version)?) {
+            warn!(
+                "Ignoring downloaded version '{}' as it is not newer than '{}'",
+                version, self.version
+            );
+            return Ok(None);
+        }
 
-        // Install LV image first as we do not want new boot entries to appear
-        // until the core image is correctly installed
-        info!(
-            "Installing LV '{}' in VG '{}'",
-            &core.name, &core.destination
-        );
+        Ok(Some(version))
+    }
 
-        let vg = match lvm::Vg::find_vg(&core.destination)? {
-            Some(v) => v,
-            None => {
-                return Err(Error::VgNotFound {
-                    vg: core.destination.clone(),
-                })
-            }
-        };
+    /// Anti-rollback floor: the highest version ever installed, or the
+    /// running version if it is higher or if none has been recorded yet
+    pub fn floor(&self) -> floor::Result<Version> {
+        let floor = floor::load(&self.floor_state())?;
+        Ok(match floor {
+            Some(f) if f > self.version => f,
+            _ => self.version.clone(),
+        })
+    }
 
-        // Parse currently mounted devices
-        let filename = "/proc/self/mountinfo";
-        let mut f = File::open(filename).context(Io { filename })?;
-        let mut content = String::new();
-        f.read_to_string(&mut content)
-            .context(Content { filename })?;
-        let mut mountpoints: Vec<MountPoint> = Vec::new();
-        for r in Parser::new(content.as_bytes()) {
-            match r {
-                Ok(m) => mountpoints.push(m),
-                Err(e) => return Err(Error::Mountinfo { source: e }),
+    /// Version the system has been rolled back from, if any. Neither this
+    /// version nor an older one is offered again until a newer one is
+    /// installed.
+    pub fn rolled_back(&self) -> floor::Result<Option<Version>> {
+        floor::load(&self.rollback_state())
+    }
+
+    /// Whether a downgrade to the given version is sanctioned by the override
+    /// kept in the state folder, verified with the trusted keys
+    pub fn sanctioned(&self, version: &Version) -> floor::Result<bool> {
+        let file = &self.downgrade_override();
+        let sigs = self.existing_sigs(&format!("{}.sig", file));
+        match floor::Override::load(file, sigs, &self.keys)? {
+            None => Ok(false),
+            Some(ref o) if o.version != *version => {
+                debug!("Downgrade override is for version '{}'", o.version);
+                Ok(false)
             }
+            Some(_) => Ok(true),
         }
+    }
 
-        // List all LV:
-        // sudo lvs --noheadings main --reportformat json | jq '.report[].lv[].lv_name'
-        // semver & find currently used lv and use the other
-        // if only one LV, add a new one
-        let lvs: Vec<lvm::Lv> = vg
-            .list_lv()?
-            .into_iter()
-            .filter(|l| {
-                let name = l.name();
+    /// Keep a downgrade override and its signatures, as fetched from url, in
+    /// the state folder, replacing any previous one
+    pub(crate) fn keep_override(
+        &self,
+        url: &str,
+        content: &[u8],
+        signatures: &[(String, Vec<u8>)],
+    ) -> floor::Result<()> {
+        self.discard_override();
+        floor::keep(&self.downgrade_override(), url, content, signatures)
+    }
 
-                // Filter LVs starting with <pkg>_.*
-                if !name.starts_with(format!("{}_", core.name).as_str()) {
-                    return false;
-                }
+    /// Remove the downgrade override and its signatures from the state folder
+    pub(crate) fn discard_override(&self) {
+        let file = &self.downgrade_override();
+        System::remove_invalid(file);
+        for sig in self.existing_sigs(&format!("{}.sig", file)) {
+            System::remove_invalid(&sig);
+        }
+    }
 
-                // Filter LVs used for swap & state
-                let mut s = name.split('_');
-                let version = match s.nth(1) {
-                    None => {
-                        warn!("invalid LV name: nothing found after 'core_': '{}'", name);
-                        return false;
-                    }
-                    Some(v) => v,
-                };
-                if version == "state" || version == "swap" {
-                    debug!("ignoring LV: '{}'", name);
-                    return false;
+    /// Report the installed versions, boot entries and downloaded payloads
+    pub fn status(&self) -> Result<Status> {
+        let mut lvs = Vec::new();
+        for pkg in self.packages(Kind::Lv) {
+            for l in System::find_vg(pkg)?.list_lv()? {
+                let name = l.name();
+                if let Some(v) = System::lv_version(pkg, &name) {
+                    lvs.push(LvStatus {
+                        current: v == self.version,
+                        version: v.to_string(),
+                        name,
+                    });
                 }
+            }
+        }
 
-                // Filter LVs with an incorrect version.
-                // This should never happen but better be safe.
-                let semver = match Version::parse(version) {
-                    Err(_e) => {
-                        warn!("could not parse '{}' as a version", version);
-                        return false;
-                    }
-                    Ok(v) => v,
-                };
+        let mut efiboot = Vec::new();
+        for pkg in self.packages(Kind::Esp) {
+            efiboot.append(&mut System::entries(pkg)?);
+        }
 
-                // Filter the currently in use version
-                debug!("comparing: '{}' & '{}'", semver, self.version);
-                if semver == self.version {
-                    return false;
-                }
+        let mut cache = Vec::new();
+        for pkg in &self.packages {
+            let path = self.cache(pkg);
+            let size = match fs::metadata(&path) {
+                Err(_e) => continue,
+                Ok(m) => m.len(),
+            };
+            cache.push(CacheStatus {
+                name: pkg.name.clone(),
+                signature: !self.existing_sigs(&self.cache_sig(pkg)).is_empty(),
+                path,
+                size,
+            });
+        }
 
-                // Check that the LV is not in use before writing to it!
-                // This should never happen, but we better be safe.
-                let verity_name = format!("/dev/mapper/verity_{}_{}", name, semver);
-                match mountpoints.iter().find(|m| {
-                    if m.mount_point.to_os_string() != "/" {
-                        debug!("Ignoring {:?} -> {:?}", &m.mount_source, &m.mount_point);
-                        return false;
-                    }
-                    debug!("Looking at {:?} -> {:?}", &m.mount_source, &m.mount_point);
-                    m.mount_source.to_os_string() == verity_name.as_str()
-                }) {
-                    Some(_mp) => {
-                        warn!("ignoring: destination currently in use!");
-                        return false;
-                    }
-                    None => debug!("proceeding: destination LV not in use"),
-                };
+        Ok(Status {
+            os_name: self.os_name.clone(),
+            version: self.version.to_string(),
+            lvs,
+            efiboot,
+            cache,
+            update_ready: Path::new(UPDATE_READY).exists(),
+        })
+    }
 
-                true
-            })
-            .collect();
+    /// Find the destination VG for an LV package
+    fn find_vg(pkg: &Package) -> Result<lvm::Vg> {
+        match lvm::Vg::find_vg(&pkg.destination)? {
+            Some(v) => Ok(v),
+            None => Err(Error::VgNotFound {
+                vg: pkg.destination.clone(),
+            }),
+        }
+    }
 
-        // TODO: Handle the case where we have more than 1 LV matching here
-        if lvs.len() > 1 {
-            warn!("More than one candidate LV found for {}", core.name);
+    /// Parse the version from a '<pkg>_<version>' LV name, ignoring LVs that
+    /// do not hold a versioned package image
+    fn lv_version(pkg: &Package, name: &str) -> Option<Version> {
+        // Filter LVs starting with <pkg>_.*
+        if !name.starts_with(format!("{}_", pkg.name).as_str()) {
+            return None;
         }
-        // Pick an LV to install the image to
-        let new_lv = format!("{}_{}", &core.name, &version);
-        let mut lv = match lvs.first() {
-            Some(l) => {
-                info!("Installing over '{}'", l.name());
-                l.clone()
-            }
+
+        // Filter LVs used for swap & state
+        let mut s = name.split('_');
+        let version = match s.nth(1) {
             None => {
-                info!("Could not find a previous installation for '{}'", core.name);
-                let size = match &core.size {
-                    Some(s) => &s,
-                    None => "500M",
-                };
-                vg.create_lv(&new_lv, size)?
+                warn!(
+                    "invalid LV name: nothing found after '{}_': '{}'",
+                    pkg.name, name
+                );
+                return None;
             }
+            Some(v) => v,
         };
+        if version == "state" || version == "swap" {
+            debug!("ignoring LV: '{}'", name);
+            return None;
+        }
 
-        // To make sure that the system is in a consistent state, we must
-        // remove boot entries before any destructive operation on the LVs.
-        // Following steps:
-        // * List all files in /mnt/efiboot/EFI/Linux
-        // * Make sure to keep the currently booted version
-        let current_efi = format!("{}-{}.efi", &self.os_name, &self.version);
-        let mut files: Vec<PathBuf> = Vec::new();
+        // Filter LVs with an incorrect version.
+        // This should never happen but better be safe.
+        match Version::parse(version) {
+            Err(_e) => {
+                warn!("could not parse '{}' as a version", version);
+                None
+            }
+            Ok(v) => Some(v),
+        }
+    }
+
+    /// List all file names in the destination folder of an ESP package
+    fn entries(pkg: &Package) -> Result<Vec<String>> {
+        let mut files: Vec<String> = Vec::new();
 
-        let dir = &efiboot.destination;
+        let dir = &pkg.destination;
         for path in Path::new(dir)
             .read_dir()
             .context(ReadDir { directory: dir })?
@@ -432,58 +971,878 @@ impl System {
                 Ok(p) => p,
             };
             match entry.file_name().to_str() {
-                None => warn!("Found invalid filename in efiboot"),
-                Some(s) => {
-                    if s != current_efi {
-                        files.push(PathBuf::from(s));
-                    }
-                }
+                None => warn!("Found invalid filename in {}", pkg.name),
+                Some(s) => files.push(String::from(s)),
             };
         }
 
-        // Warn if more than count files are remaining
-        // TODO: Handle the case where we have more than 1 efi binary matching here
-        if files.len() > 1 {
-            warn!("More than one additionnal file found for {}", efiboot.name);
-        }
-        // Remove selected files
-        for f in files {
-            let filename = &Path::new(&efiboot.destination).join(f);
-            debug!("Removing efiboot entry: {}", filename.display());
-            fs::remove_file(filename).context(Remove { filename })?;
-        }
+        files.sort();
+        Ok(files)
+    }
 
-        // We can now safely operate on unbootable LVs
-        // First, rename the LV if necessary
-        if lv.name() != new_lv {
-            lv = lv.rename_to(new_lv)?;
+    /// Download given package with corresponding version from remote
+    fn download_package(
+        &self,
+        pkg: &Package,
+        r: &Remote,
+        v: &Version,
+        expected: &ManifestPackage,
+    ) -> Result<()> {
+        let file_dst = &self.cache(pkg);
+        let sig_dst = &self.cache_sig(pkg);
+
+        // Have we already downloaded a valid file?
+        match self.validate(file_dst, sig_dst, v, Some(expected)) {
+            Err(_e) => debug!("invalid or incomplete precedent download"),
+            Ok(()) => {
+                info!("Reusing sucessfully downloaded and verified '{}'", file_dst);
+                return Ok(());
+            }
         }
 
-        // Copy the image content into the final LV
-        // TODO: Check size before calling overwriting destination LV
-        // TODO: Use casync with correct parameters
-        let lv_path = &lv.path();
-        let filename = &self.cache(core);
-        let mut img = File::open(filename).context(Io { filename })?;
-        let mut dev = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(lv_path)
-            .context(Io { filename })?;
-        io::copy(&mut img, &mut dev).context(Copy {
-            src: filename,
-            dst: lv_path,
-        })?;
+        // Download the signature first, then verify the file while it is
+        // being downloaded
+        r.mirrored(|m| self.fetch(pkg, m, file_dst, r, v, Some(expected)))?;
+        info!("Sucessfully downloaded and verified '{}'", file_dst);
+        Ok(())
+    }
 
-        // Install the EFI binary to create the boot entry
-        info!(
-            "Installing file '{}' to '{}'",
-            efiboot.name, efiboot.destination
-        );
+    /// Download package signatures and payload from mirror m to `<dst>.sig*`
+    /// and dst, verifying the payload while writing it to disk, against the
+    /// expected size and hash from the manifest if any. The payload is removed
+    /// if the verification fails.
+    fn fetch(
+        &self,
+        pkg: &Package,
+        m: &Mirror,
+        dst: &str,
+        r: &Remote,
+        v: &Version,
+        expected: Option<&ManifestPackage>,
+    ) -> Result<()> {
+        let src = &self.url(pkg, &m.dist_url, v);
+        let src_sig = &self.url_sig(pkg, &m.dist_url, v);
+        let dst_sig = &format!("{}.sig", dst);
+        let limit = expected
+            .map(|e| e.size)
+            .or(pkg.max_size)
+            .or(r.limits.max_payload_size);
 
+        // Signature files keep the `.sig` or `.sig.<n>` suffix from their URL
+        let mut fetched = Vec::new();
+        self.keys
+            .fetch_signatures(src_sig, |url| -> Result<Option<Vec<u8>>> {
+                let sig = format!("{}{}", dst_sig, &url[src_sig.len()..]);
+                match System::download_file(url, &sig, r, r.limits.max_metadata_size) {
+                    Err(Error::Source { ref source }) if source.not_found() => {
+                        debug!("No signature found at '{}'", url);
+                        Ok(None)
+                    }
+                    res => {
+                        res?;
+                        let content = fs::read(&sig).context(Io { filename: &sig })?;
+                        fetched.push(sig);
+                        Ok(Some(content))
+                    }
+                }
+            })?;
+        // Signatures left from a previous download must not be mistaken for
+        // signatures of this file
+        for sig in self.sig_files(dst_sig) {
+            if !fetched.contains(&sig) {
+                System::remove_invalid(&sig);
+            }
+        }
+        // No need to download the file if it is not the one we want or if it
+        // is not signed with enough keys
+        let sigs = self.signatures(dst, dst_sig, v)?;
+
+        // Resume a previous partial download if we know which file it was
+        // from, falling back to a full download if the server can not resume
+        // it
+        let etag = &format!("{}.etag", dst);
+        let resume = System::partial(dst, etag);
+        let mut res = System::get(src, dst, r, resume.as_ref(), limit)?;
+        let offset = res.offset;
+
+        // Without a manifest, the space needed in the cache folder has not
+        // been checked beforehand: rely on the announced length, if any.
+        // Partial content is appended to the existing file.
+        if let (None, Some(length)) = (expected, res.length) {
+            let needed = offset.unwrap_or(0) + length;
+            self.check_cache_space(needed, &[String::from(dst)])?;
+        }
+
+        let (prefix, file) = match offset {
+            Some(o) => {
+                info!("Resuming download of '{}' from byte {}", dst, o);
+                let prefix = File::open(dst).context(Io { filename: dst })?;
+                let file = OpenOptions::new()
+                    .append(true)
+                    .open(dst)
+                    .context(Io { filename: dst })?;
+                (Some(prefix.take(o)), file)
+            }
+            None => {
+                System::save_etag(res.etag.as_ref().map(String::as_str), etag)?;
+                (None, File::create(dst).context(Io { filename: dst })?)
+            }
+        };
+
+        // The previously downloaded part is read back to be verified along
+        // with the rest of the file. Stop reading once we know that the file
+        // is too large.
+        let offset = offset.unwrap_or(0);
+        let remaining = match limit {
+            None => u64::max_value(),
+            Some(l) => l.saturating_sub(offset).saturating_add(1),
+        };
+        let mut tee = Tee::new(
+            prefix,
+            Throttle::new((&mut res).take(remaining), r.rate_limit()),
+            file,
+        );
+
+        // Signatures are prehashed (minisign -H): the data is hashed as it is
+        // downloaded and not kept in memory
+        let copy = io::copy(&mut tee, &mut io::sink());
+        let Tee {
+            read_error,
+            write_error,
+            copied,
+            hash,
+            prehash,
+            ..
+        } = tee;
+        let size = offset + copied;
+        if let Some(l) = limit {
+            if size > l {
+                System::remove_invalid(dst);
+                System::remove_invalid(etag);
+                return Err(Error::TooLarge {
+                    url: src.clone(),
+                    limit: l,
+                });
+            }
+        }
+        // Keep the partial file to resume the download later
+        if let Some(e) = read_error {
+            return Err(Error::Download {
+                url: src.clone(),
+                source: e,
+            });
+        }
+        if let Some(e) = write_error {
+            return Err(Error::Copy {
+                src: PathBuf::from(src),
+                dst: PathBuf::from(dst),
+                source: e,
+            });
+        }
+        // Reading back the previously downloaded part failed
+        copy.context(Io { filename: dst })?;
+
+        System::remove_invalid(etag);
+        let checked = System::verify_prehash(&sigs, &prehash.result())
+            .and_then(|()| System::check_payload(dst, expected, size, hash));
+        if checked.is_err() {
+            System::remove_invalid(dst);
+        }
+        checked
+    }
+
+    /// Remove a downloaded package and its signatures from the cache folder
+    fn remove_cached(&self, pkg: &Package) {
+        System::remove_invalid(&self.cache(pkg));
+        for sig in self.existing_sigs(&self.cache_sig(pkg)) {
+            System::remove_invalid(&sig);
+        }
+    }
+
+    /// Bootloader packages are optional in updates: a bootloader package is
+    /// skipped if it has not been downloaded with the other packages
+    fn skipped(&self, pkg: &Package) -> bool {
+        pkg.kind == Kind::Bootloader && self.existing_sigs(&self.cache_sig(pkg)).is_empty()
+    }
+
+    /// Remove a downloaded file that failed verification or is no longer
+    /// needed
+    fn remove_invalid(filename: &str) {
+        match fs::remove_file(filename) {
+            Ok(()) => debug!("Removed file '{}'", filename),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => warn!("Could not remove file '{}': {}", filename, e),
+        }
+    }
+
+    /// Return the size and ETag of a partially downloaded file, if any
+    fn partial(dst: &str, etag: &str) -> Option<(u64, String)> {
+        let size = fs::metadata(dst).ok()?.len();
+        let tag = fs::read_to_string(etag).ok()?;
+        if size == 0 || tag.is_empty() {
+            return None;
+        }
+        Some((size, tag))
+    }
+
+    /// Mark a download as in progress until it is complete, along with its
+    /// ETag to be able to resume it safely. Downloads without an ETag can not
+    /// be resumed and get an empty marker.
+    fn save_etag(tag: Option<&str>, etag: &str) -> Result<()> {
+        fs::write(etag, tag.unwrap_or("")).context(Io { filename: etag })
+    }
+
+    /// Download URL src to file dst using remote information, reading at
+    /// most limit bytes
+    fn download_file(src: &str, dst: &str, r: &Remote, limit: u64) -> Result<()> {
+        let mut res = System::get(src, dst, r, None, Some(limit))?;
+        let mut buf = Vec::new();
+        Throttle::new((&mut res).take(limit + 1), r.rate_limit())
+            .read_to_end(&mut buf)
+            .context(Download { url: src })?;
+        if buf.len() as u64 > limit {
+            return Err(Error::TooLarge {
+                url: String::from(src),
+                limit,
+            });
+        }
+        fs::write(dst, &buf).context(Io { filename: dst })
+    }
+
+    /// Open URL src, making sure that the content is not larger than limit. If
+    /// resume is set, only get the part of the file after the given offset,
+    /// provided it still has the given ETag.
+    fn get(
+        src: &str,
+        dst: &str,
+        r: &Remote,
+        resume: Option<&(u64, String)>,
+        limit: Option<u64>,
+    ) -> Result<Response> {
+        debug!("Downloading '{}' to '{}'", src, dst);
+        let res = r.open(src, resume)?;
+
+        // Give up early if the source announces a file that is too large
+        if let (Some(l), Some(len)) = (limit, res.length) {
+            if res.offset.unwrap_or(0) + len > l {
+                return Err(Error::TooLarge {
+                    url: String::from(src),
+                    limit: l,
+                });
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Verify file using signature from sig, validating that the version match
+    fn validate(
+        &self,
+        file: &str,
+        sig: &str,
+        v: &Version,
+        expected: Option<&ManifestPackage>,
+    ) -> Result<()> {
+        let sigs = self.signatures(file, sig, v)?;
+        let f = File::open(&file).context(Io { filename: file })?;
+        let mut tee = Tee::new(None, f, io::sink());
+        io::copy(&mut tee, &mut io::sink()).context(Io { filename: file })?;
+        System::verify_prehash(&sigs, &tee.prehash.result())?;
+        System::check_payload(file, expected, tee.copied, tee.hash)
+    }
+
+    /// Verify each of the given signatures with the BLAKE2b-512 hash of the
+    /// signed file
+    fn verify_prehash(sigs: &[Signature], prehash: &[u8]) -> Result<()> {
+        for s in sigs {
+            keys::verify_prehashed(s.key, &s.signature, prehash).context(InvalidSignature {
+                filename: &s.filename,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Decode the signatures of file from sig and its numbered variants, and
+    /// select the trusted key to verify each of them with. Signatures made
+    /// with unknown or revoked keys, or with a key already used by another
+    /// signature, are ignored. Make sure that enough signatures are left and
+    /// that they are all for version v.
+    fn signatures(&self, file: &str, sig: &str, v: &Version) -> Result<Vec<Signature>> {
+        let mut sigs: Vec<Signature> = Vec::new();
+        let mut ids = Vec::new();
+        for filename in self.existing_sigs(sig) {
+            let content = fs::read_to_string(&filename).context(Io {
+                filename: &filename,
+            })?;
+            let signature = SignatureBox::from_string(&content).context(DecodeSignature {
+                filename: &filename,
+            })?;
+            let key = match self.keys.get(&content) {
+                Err(e) => {
+                    warn!("Ignoring signature '{}': {}", filename, e);
+                    continue;
+                }
+                Ok(k) => k,
+            };
+            let id = keys::key_id(&content);
+            if ids.contains(&id) {
+                warn!("Ignoring signature '{}': key already used", filename);
+                continue;
+            }
+            System::check_prehashed(&signature, &filename)?;
+            System::check_version(&signature, &filename, v)?;
+            ids.push(id);
+            sigs.push(Signature {
+                filename,
+                signature,
+                key,
+            });
+        }
+
+        let threshold = self.keys.threshold();
+        if sigs.len() < threshold {
+            return Err(Error::NotEnoughSignatures {
+                filename: PathBuf::from(file),
+                valid: sigs.len(),
+                threshold,
+            });
+        }
+        Ok(sigs)
+    }
+
+    /// Validate that a payload of the given size and SHA-256 hash matches the
+    /// manifest entry for this package, if any
+    fn check_payload(
+        file: &str,
+        expected: Option<&ManifestPackage>,
+        size: u64,
+        hash: digest::Context,
+    ) -> Result<()> {
+        let e = match expected {
+            None => return Ok(()),
+            Some(e) => e,
+        };
+        let hash = hash.finish();
+        if size != e.size || hash.as_ref() != &e.sha256[..] {
+            return Err(Error::PayloadMismatch {
+                filename: PathBuf::from(file),
+                expected_size: e.size,
+                expected_hash: manifest::to_hex(&e.sha256),
+                size,
+                hash: manifest::to_hex(hash.as_ref()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Refuse signatures made in the legacy mode, payloads are hashed while
+    /// they are downloaded
+    fn check_prehashed(s: &SignatureBox, sig: &str) -> Result<()> {
+        if !s.is_prehashed() {
+            return Err(Error::NotPrehashed {
+                filename: PathBuf::from(sig),
+            });
+        }
+        Ok(())
+    }
+
+    /// Validate that the version in the trusted comment of signature sig
+    /// matches
+    fn check_version(s: &SignatureBox, sig: &str, v: &Version) -> Result<()> {
+        let trusted_comment = s
+            .trusted_comment()
+            .context(InvalidTrustedComment { filename: sig })?;
+        let version = Version::parse(&trusted_comment).context(InvalidVersion {
+            version: trusted_comment,
+        })?;
+
+        if version != *v {
+            return Err(Error::VersionMismatch {
+                expected: (*v).clone(),
+                comment: version,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Install the system update from packages previously downloaded and
+    /// validated in the cache folder
+    pub fn install(&self, version: &Version) -> Result<()> {
+        let plan = self.plan(version)?;
+        self.apply(&plan)
+    }
+
+    /// Select the destinations for the update and list all actions needed to
+    /// install it, without modifying the system
+    pub fn plan(&self, version: &Version) -> Result<InstallPlan> {
+        // Versions below the floor are only installed if sanctioned
+        let floor = self.floor()?;
+        let downgrade = *version < floor;
+        if downgrade && !self.sanctioned(version)? {
+            return Err(Error::Floor {
+                source: floor::Error::BelowFloor {
+                    version: version.clone(),
+                    floor,
+                },
+            });
+        }
+
+        // Parse currently mounted devices
+        let filename = "/proc/self/mountinfo";
+        let mut f = File::open(filename).context(Io { filename })?;
+        let mut content = String::new();
+        f.read_to_string(&mut content)
+            .context(Content { filename })?;
+        let mut mountpoints: Vec<MountPoint> = Vec::new();
+        for r in Parser::new(content.as_bytes()) {
+            match r {
+                Ok(m) => mountpoints.push(m),
+                Err(e) => return Err(Error::Mountinfo { source: e }),
+            }
+        }
+
+        let mut steps = Vec::new();
+        for pkg in self.packages.iter().filter(|p| !self.skipped(p)) {
+            let image = self.cache(pkg);
+            let size = fs::metadata(&image).context(Io { filename: &image })?.len();
+            let action = match pkg.kind {
+                Kind::Lv => self.plan_lv(pkg, version, &mountpoints, size)?,
+                Kind::Esp | Kind::File => Action::CopyFile {
+                    dest: self.dest(pkg, version),
+                },
+                Kind::Bootloader => {
+                    let dests: Vec<String> = System::bootloader_dests(pkg)
+                        .into_iter()
+                        .filter(|d| !System::installed(&image, d))
+                        .collect();
+                    if dests.is_empty() {
+                        info!("Bootloader '{}' is already installed", pkg.name);
+                        continue;
+                    }
+                    Action::ReplaceBootloader { dests }
+                }
+            };
+            steps.push(Step {
+                package: pkg.name.clone(),
+                kind: pkg.kind,
+                image,
+                size,
+                action,
+            });
+        }
+
+        // To make sure that the system is in a consistent state, we must
+        // remove boot entries before any destructive operation on the LVs.
+        // Following steps:
+        // * List all files in /mnt/efiboot/EFI/Linux
+        // * Make sure to keep the currently booted version
+        let mut remove: Vec<PathBuf> = Vec::new();
+        for pkg in self.packages(Kind::Esp) {
+            let current = format!("{}-{}.efi", &self.os_name, &self.version);
+            let files: Vec<PathBuf> = System::entries(pkg)?
+                .into_iter()
+                .filter(|s| *s != current)
+                .map(|s| Path::new(&pkg.destination).join(s))
+                .collect();
+
+            // Warn if more than count files are remaining
+            // TODO: Handle the case where we have more than 1 efi binary matching here
+            if files.len() > 1 {
+                warn!("More than one additionnal file found for {}", pkg.name);
+            }
+            remove.extend(files);
+        }
+
+        // Check available space before doing anything
+        System::check_space(&steps, &remove)?;
+
+        Ok(InstallPlan {
+            version: version.clone(),
+            downgrade,
+            remove,
+            steps,
+        })
+    }
+
+    /// Make sure that every installation step has enough free space on its
+    /// destination. Files and EFI binaries landing on the same filesystem are
+    /// added up.
+    fn check_space(steps: &[Step], remove: &[PathBuf]) -> Result<()> {
+        // Space needed on each filesystem, identified by its device number,
+        // with the first destination found on it and whether it is an ESP
+        let mut filesystems: BTreeMap<u64, (PathBuf, bool, u64)> = BTreeMap::new();
+        let mut add = |path: &Path, esp: bool, needed: u64| -> Result<()> {
+            let dir = path.parent().unwrap_or_else(|| Path::new("/"));
+            let dev = fs::metadata(dir).context(Filesystem { path: dir })?.dev();
+            let usage = filesystems
+                .entry(dev)
+                .or_insert_with(|| (path.to_path_buf(), false, 0));
+            usage.1 |= esp;
+            usage.2 = usage.2.checked_add(needed).context(SizeOverflow {
+                target: format!("'{}'", usage.0.display()),
+            })?;
+            Ok(())
+        };
+        // Space taken from the free space of each VG by new and extended LVs
+        let mut vgs: BTreeMap<String, (lvm::Vg, u64)> = BTreeMap::new();
+        let mut take = |vg: lvm::Vg, needed: u64| -> Result<()> {
+            let usage = vgs.entry(vg.name.clone()).or_insert_with(|| (vg, 0));
+            usage.1 = usage.1.checked_add(needed).context(SizeOverflow {
+                target: format!("VG '{}'", usage.0.name),
+            })?;
+            Ok(())
+        };
+
+        for step in steps {
+            match &step.action {
+                Action::OverwriteLv {
+                    lv,
+                    size,
+                    grow: Some(grow),
+                    ..
+                } => {
+                    // The LV can only be extended within the VG free space
+                    let vg = lv.vg();
+                    let free = vg.free()?;
+                    if size.saturating_add(free) < *grow {
+                        return Err(Error::LvTooSmall {
+                            lv: lv.path(),
+                            needed: *grow,
+                            available: size.saturating_add(free),
+                        });
+                    }
+                    take(vg, grow.saturating_sub(*size))?;
+                }
+                Action::OverwriteLv { grow: None, .. } => (),
+                Action::CreateLv { vg, size, .. } => {
+                    let needed = parse_size(size).context(InvalidSize { size: size.clone() })?;
+                    take(vg.clone(), needed)?;
+                }
+                Action::CopyFile { dest } => {
+                    add(Path::new(dest), step.kind == Kind::Esp, step.size)?
+                }
+                Action::ReplaceBootloader { dests } => {
+                    for dest in dests {
+                        // Room is needed for both the backup and the new binary
+                        let path = Path::new(dest);
+                        let backup = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+                        let needed = step.size.checked_add(backup).context(SizeOverflow {
+                            target: format!("'{}'", path.display()),
+                        })?;
+                        add(path, true, needed)?;
+                    }
+                }
+            }
+        }
+
+        for (name, (vg, needed)) in vgs {
+            let available = vg.free()?;
+            if available < needed {
+                return Err(Error::NoVgSpace {
+                    vg: name,
+                    needed,
+                    available,
+                });
+            }
+        }
+
+        for (dev, (path, esp, needed)) in filesystems {
+            // Files removed from the same filesystem beforehand free some space
+            let freed: u64 = remove
+                .iter()
+                .filter_map(|r| fs::metadata(r).ok())
+                .filter(|m| m.dev() == dev)
+                .map(|m| m.len())
+                .sum();
+            let dir = path.parent().unwrap_or_else(|| Path::new("/"));
+            let available = System::free_space(dir)? + freed;
+            if available < needed {
+                return Err(if esp {
+                    Error::NoEspSpace {
+                        path,
+                        needed,
+                        available,
+                    }
+                } else {
+                    Error::NoFileSpace {
+                        path,
+                        needed,
+                        available,
+                    }
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the space available to unprivileged users on the filesystem
+    /// holding path, in bytes
+    fn free_space(path: &Path) -> Result<u64> {
+        let st = statvfs(path).context(Statvfs { path })?;
+        Ok(st.blocks_available() as u64 * st.fragment_size() as u64)
+    }
+
+    /// Select the LV to install an LV package to
+    fn plan_lv(
+        &self,
+        pkg: &Package,
+        version: &Version,
+        mountpoints: &[MountPoint],
+        image_size: u64,
+    ) -> Result<Action> {
+        let vg = System::find_vg(pkg)?;
+
+        // List all LV:
+        // sudo lvs --noheadings main --reportformat json | jq '.report[].lv[].lv_name'
+        // semver & find currently used lv and use the other
+        // if only one LV, add a new one
+        let lvs: Vec<lvm::Lv> = vg
+            .list_lv()?
+            .into_iter()
+            .filter(|l| {
+                let name = l.name();
+
+                let semver = match System::lv_version(pkg, &name) {
+                    None => return false,
+                    Some(v) => v,
+                };
+
+                // Filter the currently in use version
+                debug!("comparing: '{}' & '{}'", semver, self.version);
+                if semver == self.version {
+                    return false;
+                }
+
+                // Check that the LV is not in use before writing to it!
+                // This should never happen, but we better be safe.
+                let verity_name = format!("/dev/mapper/verity_{}_{}", name, semver);
+                match mountpoints.iter().find(|m| {
+                    if m.mount_point.to_os_string() != "/" {
+                        debug!("Ignoring {:?} -> {:?}", &m.mount_source, &m.mount_point);
+                        return false;
+                    }
+                    debug!("Looking at {:?} -> {:?}", &m.mount_source, &m.mount_point);
+                    m.mount_source.to_os_string() == verity_name.as_str()
+                }) {
+                    Some(_mp) => {
+                        warn!("ignoring: destination currently in use!");
+                        return false;
+                    }
+                    None => debug!("proceeding: destination LV not in use"),
+                };
+
+                true
+            })
+            .collect();
+
+        // TODO: Handle the case where we have more than 1 LV matching here
+        if lvs.len() > 1 {
+            warn!("More than one candidate LV found for {}", pkg.name);
+        }
+        // Pick an LV to install the image to
+        let name = format!("{}_{}", &pkg.name, version);
+        Ok(match lvs.first() {
+            Some(l) => {
+                debug!("Selected '{}' as destination", l.name());
+                let size = l.size()?;
+                let grow = if size < image_size {
+                    debug!(
+                        "Image does not fit in '{}' ({} < {} bytes)",
+                        l.name(),
+                        size,
+                        image_size
+                    );
+                    Some(image_size)
+                } else {
+                    None
+                };
+                Action::OverwriteLv {
+                    lv: l.clone(),
+                    name,
+                    size,
+                    grow,
+                }
+            }
+            None => {
+                debug!("Could not find a previous installation for '{}'", pkg.name);
+                let mut size = match &pkg.size {
+                    Some(s) => s.clone(),
+                    None => String::from("500M"),
+                };
+                // Never create an LV smaller than the image
+                match parse_size(&size) {
+                    Some(s) if s >= image_size => (),
+                    _ => size = format!("{}b", image_size),
+                }
+                Action::CreateLv { vg, name, size }
+            }
+        })
+    }
+
+    /// Apply all actions from an installation plan
+    fn apply(&self, plan: &InstallPlan) -> Result<()> {
+        // Remove selected files
+        for filename in &plan.remove {
+            debug!("Removing efiboot entry: {}", filename.display());
+            fs::remove_file(filename).context(Remove { filename })?;
+        }
+
+        // We can now safely operate on unbootable LVs
+        for step in &plan.steps {
+            let filename = &step.image;
+            match &step.action {
+                Action::OverwriteLv { lv, name, grow, .. } => {
+                    info!("Installing '{}' over LV '{}'", step.package, lv.name());
+                    // The kept copies of packages for the version currently in
+                    // this LV can not be used for a rollback anymore
+                    if let Some(pkg) = self.packages.iter().find(|p| p.name == step.package) {
+                        if let Some(v) = System::lv_version(pkg, &lv.name()) {
+                            self.remove_kept(&v);
+                        }
+                    }
+
+                    // First, rename the LV if necessary
+                    let lv = if lv.name() != *name {
+                        lv.rename_to(name.clone())?
+                    } else {
+                        lv.clone()
+                    };
+                    // Then make sure that the image fits
+                    if let Some(g) = grow {
+                        info!("Extending LV '{}' to {} bytes", lv.name(), g);
+                        lv.resize(g)?;
+                    }
+                    System::copy_to_lv(filename, &lv)?;
+                }
+                Action::CreateLv { vg, name, size } => {
+                    info!(
+                        "Installing '{}' to new LV '{}' in VG '{}'",
+                        step.package, name, vg.name
+                    );
+                    let lv = vg.create_lv(name, size)?;
+                    System::copy_to_lv(filename, &lv)?;
+                }
+                Action::CopyFile { dest } => {
+                    info!("Installing file '{}' to '{}'", step.package, dest);
+                    System::install_file(filename, dest)?;
+                }
+                Action::ReplaceBootloader { dests } => {
+                    info!("Replacing bootloader with '{}'", step.package);
+                    System::replace_bootloader(filename, dests)?;
+                }
+            }
+        }
+
+        // Versions below this one are refused from now on. A sanctioned
+        // downgrade lowers the floor and the override can not be used again.
+        floor::save(&self.floor_state(), &plan.version)?;
+        if plan.downgrade {
+            self.discard_override();
+        }
+        System::remove_invalid(&self.rollback_state());
+
+        // Make the new entry the default one if a default entry has been set
+        // (for example by a previous rollback)
+        if Path::new(LOADER_ENTRY_DEFAULT).exists() && self.packages(Kind::Esp).count() > 0 {
+            let entry = format!("{}-{}.efi", &self.os_name, &plan.version);
+            System::set_default_entry(&entry)
+                .unwrap_or_else(|e| warn!("Could not set default boot entry: {}", e));
+        }
+
+        // As the update completed successfully, we can now remove the LV
+        // images. Their signatures and sizes are kept to verify the LVs before
+        // a rollback to this version. Errors are ignored here as they are not
+        // fatal and should never happen.
+        for pkg in self.packages(Kind::Lv) {
+            self.keep_lv(pkg, &plan.version);
+            fs::remove_file(self.cache(pkg))
+                .unwrap_or_else(|e| warn!("Could not remove temporary file: {}", e));
+        }
+
+        // Keep the files and their signatures to be able to restore this
+        // version later. It also marks the installation as completed.
+        for pkg in self
+            .packages
+            .iter()
+            .filter(|p| p.kind != Kind::Lv && !self.skipped(p))
+        {
+            let kept = &self.kept(pkg, &plan.version);
+            fs::rename(self.cache(pkg), kept)
+                .unwrap_or_else(|e| warn!("Could not keep file '{}': {}", kept, e));
+            self.keep_sigs(pkg, &plan.version);
+        }
+
+        Ok(())
+    }
+
+    /// Record the size of the image of an LV package installed for version
+    /// v and keep its signatures
+    fn keep_lv(&self, pkg: &Package, v: &Version) {
+        let kept_size = &self.kept_size(pkg, v);
+        match fs::metadata(self.cache(pkg)) {
+            Ok(m) => fs::write(kept_size, m.len().to_string())
+                .unwrap_or_else(|e| warn!("Could not keep file '{}': {}", kept_size, e)),
+            Err(e) => warn!("Could not keep file '{}': {}", kept_size, e),
+        }
+        self.keep_sigs(pkg, v);
+    }
+
+    /// Move the downloaded signatures of a package installed for version v
+    /// next to its kept copy
+    fn keep_sigs(&self, pkg: &Package, v: &Version) {
+        for (sig, kept_sig) in self
+            .sig_files(&self.cache_sig(pkg))
+            .iter()
+            .zip(self.sig_files(&self.kept_sig(pkg, v)))
+        {
+            if Path::new(sig).exists() {
+                fs::rename(sig, &kept_sig)
+                    .unwrap_or_else(|e| warn!("Could not keep file '{}': {}", kept_sig, e));
+            }
+        }
+    }
+
+    /// Remove kept copies, LV image sizes and signatures of packages for the
+    /// given version
+    fn remove_kept(&self, v: &Version) {
+        for pkg in &self.packages {
+            let kept = match pkg.kind {
+                Kind::Lv => self.kept_size(pkg, v),
+                _ => self.kept(pkg, v),
+            };
+            fs::remove_file(kept).unwrap_or_else(|e| {
+                debug!("Could not remove kept file for version '{}': {}", v, e)
+            });
+            for sig in self.existing_sigs(&self.kept_sig(pkg, v)) {
+                fs::remove_file(sig).unwrap_or_else(|e| {
+                    debug!("Could not remove kept file for version '{}': {}", v, e)
+                });
+            }
+        }
+    }
+
+    /// Copy the image content into the final LV
+    fn copy_to_lv(filename: &str, lv: &lvm::Lv) -> Result<()> {
+        // TODO: Use casync with correct parameters
+        let lv_path = &lv.path();
+        let mut img = File::open(filename).context(Io { filename })?;
+        let mut dev = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(lv_path)
+            .context(Io { filename })?;
+        io::copy(&mut img, &mut dev).context(Copy {
+            src: filename,
+            dst: lv_path,
+        })?;
+        Ok(())
+    }
+
+    /// Copy a file under a temporary name, then rename it to its destination
+    fn install_file(filename: &str, dest: &str) -> Result<()> {
         // First copy under a temporary name
-        let filename = &self.cache(efiboot);
-        let fullpath = &format!("{}.new", self.dest(efiboot, version));
+        let fullpath = &format!("{}.new", dest);
         fs::copy(filename, fullpath).context(Copy {
             src: filename,
             dst: fullpath,
@@ -497,23 +1856,673 @@ impl System {
             .context(Sync {})?;
 
         // Rename to the final name
-        let final_path = &self.dest(efiboot, version);
-        fs::rename(fullpath, final_path).context(Rename {
+        fs::rename(fullpath, dest).context(Rename {
             src: fullpath,
-            dst: final_path,
+            dst: dest,
         })?;
+        Ok(())
+    }
+
+    /// Check whether file dest has the same content as file filename
+    fn installed(filename: &str, dest: &str) -> bool {
+        match (fs::read(filename), fs::read(dest)) {
+            (Ok(new), Ok(old)) => new == old,
+            _ => false,
+        }
+    }
+
+    /// Backup and replace the bootloader binaries. Each binary is replaced
+    /// atomically and the previous one is kept as '<binary>.bak'.
+    fn replace_bootloader(filename: &str, dests: &[String]) -> Result<()> {
+        for dest in dests {
+            // Keep the backup of the previous binary if this one is already
+            // installed
+            if System::installed(filename, dest) {
+                debug!("'{}' is already installed as '{}'", filename, dest);
+                continue;
+            }
+            if Path::new(dest).exists() {
+                let backup = &format!("{}.bak", dest);
+                debug!("Saving '{}' as '{}'", dest, backup);
+                fs::copy(dest, backup).context(Copy {
+                    src: dest,
+                    dst: backup,
+                })?;
+            }
+            System::install_file(filename, dest)?;
+        }
+        Ok(())
+    }
+
+    /// Find the version installed in the other LVs, to be used for a rollback.
+    /// Only versions older than the running one are considered: a newer
+    /// version is an update, not a rollback.
+    pub fn rollback_target(&self) -> Result<Version> {
+        // Only consider versions installed for all LV packages
+        let mut versions: Option<Vec<Version>> = None;
+        for pkg in self.packages(Kind::Lv) {
+            let found: Vec<Version> = System::find_vg(pkg)?
+                .list_lv()?
+                .into_iter()
+                .filter_map(|l| System::lv_version(pkg, &l.name()))
+                .filter(|v| *v < self.version)
+                .collect();
+            versions = Some(match versions {
+                None => found,
+                Some(mut v) => {
+                    v.retain(|x| found.contains(x));
+                    v
+                }
+            });
+        }
+        let mut versions = versions.unwrap_or_default();
+        versions.sort();
+
+        if versions.len() > 1 {
+            warn!("More than one candidate version found for rollback");
+        }
+        match versions.pop() {
+            None => Err(Error::NoRollbackTarget {
+                version: self.version.clone(),
+            }),
+            Some(v) => Ok(v),
+        }
+    }
+
+    /// Plan the rollback to the given previously installed version, after
+    /// making sure that its LVs still exist and that its installation
+    /// completed. Kept copies of files that are not valid anymore are
+    /// planned to be downloaded again.
+    pub fn rollback_plan(&self, version: &Version) -> Result<RollbackPlan> {
+        let mut lvs = Vec::new();
+        for pkg in self.packages(Kind::Lv) {
+            let lv = self.dest(pkg, version);
+            if !Path::new(&lv).exists() {
+                return Err(Error::NotIntact {
+                    lv,
+                    reason: String::from("LV not found or not active"),
+                });
+            }
+            lvs.push(lv);
+        }
+
+        // EFI binaries are only installed or kept once the corresponding LV
+        // images have been completely written. If we have neither, the
+        // installation has been interrupted and the LVs are unusable.
+        for pkg in self.packages(Kind::Esp) {
+            if !Path::new(&self.dest(pkg, version)).exists()
+                && self.existing_sigs(&self.kept_sig(pkg, version)).is_empty()
+            {
+                return Err(Error::NotIntact {
+                    lv: version.to_string(),
+                    reason: String::from("no record of a completed installation"),
+                });
+            }
+        }
+
+        let mut steps = Vec::new();
+        for pkg in self.packages.iter().filter(|p| p.kind != Kind::Lv) {
+            let dest = self.dest(pkg, version);
+            if pkg.kind == Kind::Esp && Path::new(&dest).exists() {
+                info!("Boot entry '{}' is still installed", dest);
+                continue;
+            }
+
+            let kept = self.kept(pkg, version);
+            let kept_sig = &self.kept_sig(pkg, version);
+            // The bootloader is left as is if it was not part of this version
+            if pkg.kind == Kind::Bootloader && self.existing_sigs(kept_sig).is_empty() {
+                info!("No bootloader kept for version '{}'", version);
+                continue;
+            }
+            let download = match self.validate(&kept, kept_sig, version, None) {
+                Ok(()) => false,
+                Err(e) => {
+                    warn!("Kept copy '{}' is not valid: {}", kept, e);
+                    true
+                }
+            };
+            let dests = match pkg.kind {
+                Kind::Bootloader => System::bootloader_dests(pkg),
+                _ => vec![dest],
+            };
+            steps.push(RollbackStep {
+                package: pkg.name.clone(),
+                kind: pkg.kind,
+                kept,
+                download,
+                dests,
+            });
+        }
+
+        let entry = if self.packages(Kind::Esp).count() > 0 {
+            Some(format!("{}-{}.efi", &self.os_name, version))
+        } else {
+            None
+        };
+        Ok(RollbackPlan {
+            version: version.clone(),
+            lvs,
+            steps,
+            entry,
+        })
+    }
+
+    /// Restore the boot entries and files for the given previously installed
+    /// version and make it the default boot entry.
+    /// Rollback steps:
+    /// 1. Make sure that the LVs for this version are intact: their content
+    ///    is verified against the signatures kept when they were installed
+    /// 2. Reinstall EFI binaries and files from the kept copies, or download
+    ///    them again if the kept copies are not valid anymore
+    /// 3. Set the EFI binary as default boot entry
+    /// 4. Record the running version so that it is not installed again
+    pub fn rollback(&self, remote: &Remote, version: &Version) -> Result<()> {
+        info!("Rolling back to version '{}'", version);
+
+        let plan = self.rollback_plan(version)?;
+        for pkg in self.packages(Kind::Lv) {
+            let lv = &self.dest(pkg, version);
+            info!("Verifying LV '{}'", lv);
+            self.verify_lv(pkg, version, lv)?;
+        }
+
+        for step in &plan.steps {
+            let pkg = match self.packages.iter().find(|p| p.name == step.package) {
+                Some(p) => p,
+                None => continue,
+            };
+            if step.download {
+                remote.mirrored(|m| self.fetch(pkg, m, &step.kept, remote, version, None))?;
+            }
+
+            if step.kind == Kind::Bootloader {
+                info!("Replacing bootloader with '{}'", step.kept);
+                System::replace_bootloader(&step.kept, &step.dests)?;
+            } else {
+                for dest in &step.dests {
+                    info!("Installing file '{}' to '{}'", step.kept, dest);
+                    System::install_file(&step.kept, dest)?;
+                }
+            }
+        }
+
+        if let Some(entry) = &plan.entry {
+            System::set_default_entry(entry)?;
+        }
+
+        // Otherwise the next update would install the running version again
+        // and make it the default boot entry, undoing the rollback
+        floor::save(&self.rollback_state(), &self.version)?;
+        Ok(())
+    }
+
+    /// Verify the image written to the LV of a package for the given version
+    /// against the size and signatures kept when it was installed. The LV may
+    /// be larger than the image.
+    fn verify_lv(&self, pkg: &Package, version: &Version, lv: &str) -> Result<()> {
+        let not_intact = |reason: String| Error::NotIntact {
+            lv: String::from(lv),
+            reason,
+        };
+        let size = fs::read_to_string(self.kept_size(pkg, version))
+            .ok()
+            .and_then(|s| s.trim().parse::<u64>().ok())
+            .ok_or_else(|| not_intact(String::from("no record of the installed image")))?;
+        let sigs = self
+            .signatures(lv, &self.kept_sig(pkg, version), version)
+            .map_err(|e| not_intact(e.to_string()))?;
+
+        let f = File::open(lv).context(Io { filename: lv })?;
+        let mut tee = Tee::new(None, f.take(size), io::sink());
+        io::copy(&mut tee, &mut io::sink()).context(Io { filename: lv })?;
+        if tee.copied != size {
+            return Err(not_intact(format!(
+                "LV is smaller than the installed image ({} < {} bytes)",
+                tee.copied, size
+            )));
+        }
+        System::verify_prehash(&sigs, &tee.prehash.result()).map_err(|e| not_intact(e.to_string()))
+    }
+
+    /// Set the default systemd-boot entry
+    fn set_default_entry(entry: &str) -> Result<()> {
+        debug!("Setting default boot entry to '{}'", entry);
+        let status = Command::new("bootctl")
+            .args(&["set-default", entry])
+            .status()
+            .context(Bootctl {})?;
+        if !status.success() {
+            return Err(Error::BootctlFailed {
+                entry: String::from(entry),
+            });
+        }
+        Ok(())
+    }
+}
 
-        // As the update completed successfully, we can now remove temporary files.
-        // Errors are ignored here as they are not fatal and should never happen.
-        fs::remove_file(self.cache(core))
-            .unwrap_or_else(|e| warn!("Could not remove temporary file: {}", e));
-        fs::remove_file(self.cache_sig(core))
-            .unwrap_or_else(|e| warn!("Could not remove temporary file: {}", e));
-        fs::remove_file(self.cache(efiboot))
-            .unwrap_or_else(|e| warn!("Could not remove temporary file: {}", e));
-        fs::remove_file(self.cache_sig(efiboot))
-            .unwrap_or_else(|e| warn!("Could not remove temporary file: {}", e));
+impl fmt::Display for InstallPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Installation plan for version '{}':", self.version)?;
+        if self.downgrade {
+            write!(f, "\n  * Downgrade below the anti-rollback floor")?;
+        }
+        for r in &self.remove {
+            write!(f, "\n  * Remove EFI binary '{}'", r.display())?;
+        }
+        for s in &self.steps {
+            match &s.action {
+                Action::OverwriteLv {
+                    lv,
+                    name,
+                    size,
+                    grow,
+                } => {
+                    if lv.name() != *name {
+                        write!(f, "\n  * Rename LV '{}' to '{}'", lv.name(), name)?;
+                    }
+                    if let Some(g) = grow {
+                        write!(f, "\n  * Extend LV '{}' from {} to {} bytes", name, size, g)?;
+                    }
+                    write!(
+                        f,
+                        "\n  * Copy '{}' ({} bytes) to LV '{}'",
+                        s.image, s.size, name
+                    )?;
+                }
+                Action::CreateLv { vg, name, size } => {
+                    write!(
+                        f,
+                        "\n  * Create LV '{}' with size '{}' in VG '{}'",
+                        name, size, vg.name
+                    )?;
+                    write!(
+                        f,
+                        "\n  * Copy '{}' ({} bytes) to LV '{}'",
+                        s.image, s.size, name
+                    )?;
+                }
+                Action::CopyFile { dest } => {
+                    write!(
+                        f,
+                        "\n  * Copy '{}' ({} bytes) to '{}'",
+                        s.image, s.size, dest
+                    )?;
+                }
+                Action::ReplaceBootloader { dests } => {
+                    for d in dests {
+                        write!(f, "\n  * Backup '{}' to '{}.bak'", d, d)?;
+                        write!(f, "\n  * Copy '{}' ({} bytes) to '{}'", s.image, s.size, d)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
 
+impl fmt::Display for RollbackPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Rollback plan for version '{}':", self.version)?;
+        for lv in &self.lvs {
+            write!(f, "\n  * Verify LV '{}' against its kept signatures", lv)?;
+        }
+        for s in &self.steps {
+            if s.download {
+                write!(f, "\n  * Download '{}' again to '{}'", s.package, s.kept)?;
+            }
+            for d in &s.dests {
+                if s.kind == Kind::Bootloader {
+                    write!(f, "\n  * Backup '{}' to '{}.bak'", d, d)?;
+                }
+                write!(f, "\n  * Copy '{}' to '{}'", s.kept, d)?;
+            }
+        }
+        if let Some(e) = &self.entry {
+            write!(f, "\n  * Set default boot entry to '{}'", e)?;
+        }
         Ok(())
     }
 }
+
+/// Signature of a file, along with the trusted key to verify it with
+struct Signature<'a> {
+    filename: String,
+    signature: SignatureBox,
+    key: &'a TrustedKey,
+}
+
+/// Reader copying everything read from `reader` to `writer`, used to write a
+/// download to disk while it is being verified. Everything in `prefix` is
+/// read first and not copied. Download and write errors are kept to
+/// distinguish them from verification failures. Everything read is hashed to
+/// be checked against the manifest and the prehashed signatures.
+struct Tee<R: Read, W: Write> {
+    prefix: Option<io::Take<File>>,
+    reader: R,
+    writer: W,
+    copied: u64,
+    hash: digest::Context,
+    prehash: Blake2b,
+    read_error: Option<io::Error>,
+    write_error: Option<io::Error>,
+}
+
+impl<R: Read, W: Write> Tee<R, W> {
+    fn new(prefix: Option<io::Take<File>>, reader: R, writer: W) -> Tee<R, W> {
+        Tee {
+            prefix,
+            reader,
+            writer,
+            copied: 0,
+            hash: digest::Context::new(&digest::SHA256),
+            prehash: Blake2b::new(),
+            read_error: None,
+            write_error: None,
+        }
+    }
+}
+
+impl<R: Read, W: Write> Read for Tee<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Data already on disk is only read back
+        if let Some(p) = &mut self.prefix {
+            match p.read(buf) {
+                Ok(0) => self.prefix = None,
+                Ok(n) => {
+                    self.hash.update(&buf[..n]);
+                    self.prehash.input(&buf[..n]);
+                    return Ok(n);
+                }
+                res => return res,
+            }
+        }
+
+        let n = match self.reader.read(buf) {
+            Ok(n) => n,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::Interrupted {
+                    self.read_error = Some(io::Error::new(e.kind(), e.to_string()));
+                }
+                return Err(e);
+            }
+        };
+        if let Err(e) = self.writer.write_all(&buf[..n]) {
+            self.write_error = Some(io::Error::new(e.kind(), e.to_string()));
+            return Err(e);
+        }
+        self.hash.update(&buf[..n]);
+        self.prehash.input(&buf[..n]);
+        self.copied += n as u64;
+        Ok(n)
+    }
+}
+
+/// Reader limiting the average rate at which data is read from `reader`, in
+/// bytes per second
+struct Throttle<R: Read> {
+    reader: R,
+    rate: Option<u64>,
+    start: Instant,
+    read: u64,
+}
+
+impl<R: Read> Throttle<R> {
+    fn new(reader: R, rate: Option<u64>) -> Throttle<R> {
+        Throttle {
+            reader,
+            rate,
+            start: Instant::now(),
+            read: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Throttle<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let rate = match self.rate {
+            None | Some(0) => return self.reader.read(buf),
+            Some(r) => r,
+        };
+
+        // Read at most about a tenth of a second worth of data at once to
+        // keep the rate smooth
+        let max = std::cmp::max(rate / 10, 1) as usize;
+        let len = std::cmp::min(buf.len(), max);
+        let n = self.reader.read(&mut buf[..len])?;
+        self.read += n as u64;
+
+        // Wait until the average rate is back under the limit
+        let expected = Duration::from_millis(self.read.saturating_mul(1000) / rate);
+        let elapsed = self.start.elapsed();
+        if expected > elapsed {
+            thread::sleep(expected - elapsed);
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Manifest;
+    use crate::state::test_dir;
+
+    /// Local mirror serving the test update
+    fn remote() -> Remote {
+        let webroot = format!("file://{}/webroot", env!("CARGO_MANIFEST_DIR"));
+        Remote::test(vec![Mirror {
+            update_url: format!("{}/update/v1", webroot),
+            dist_url: format!("{}/dist", webroot),
+            priority: 0,
+        }])
+    }
+
+    /// System running version 5.0.0-alpha.1 with a single core package, using
+    /// the given folder as download cache
+    fn system(dir: &str, max_size: Option<u64>) -> System {
+        let config = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/client/config");
+        let package = Package {
+            kind: Kind::Lv,
+            name: String::from("core"),
+            destination: String::from("mainvg"),
+            size: None,
+            order: 1,
+            max_size,
+        };
+        System::new(
+            String::from("clipos"),
+            vec![package],
+            Version::parse("5.0.0-alpha.1").unwrap(),
+            Keys::load(&config, 1).unwrap(),
+            String::from(dir),
+            String::from(dir),
+        )
+    }
+
+    /// Expected core package from the test manifest
+    fn expected(system: &System) -> ManifestPackage {
+        let manifest = include_bytes!("../webroot/update/v1/clipos/manifest");
+        let sig = include_bytes!("../webroot/update/v1/clipos/manifest.sig");
+        let signatures = [(String::from("manifest.sig"), sig.to_vec())];
+        let m = Manifest::verify("manifest", manifest, &signatures, system.keys(), None).unwrap();
+        let core = m.package("core").unwrap();
+        ManifestPackage {
+            name: core.name.clone(),
+            size: core.size,
+            sha256: core.sha256.clone(),
+        }
+    }
+
+    /// Download the core package of the test update to the cache folder
+    fn fetch(system: &System, r: &Remote, expected: Option<&ManifestPackage>) -> Result<()> {
+        let pkg = &system.packages[0];
+        let v = Version::parse("5.0.0-alpha.3").unwrap();
+        system.fetch(pkg, &r.mirrors[0], &system.cache(pkg), r, &v, expected)
+    }
+
+    fn too_large(res: Result<()>, expected: u64) {
+        match res {
+            Err(Error::TooLarge { limit, .. }) => assert_eq!(limit, expected),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn fetch_within_limits() {
+        let dir = test_dir("system-fetch");
+        let system = system(&dir, Some(27));
+        let mut r = remote();
+        r.limits.max_payload_size = Some(27);
+        fetch(&system, &r, Some(&expected(&system))).unwrap();
+        let cache = system.cache(&system.packages[0]);
+        assert_eq!(fs::metadata(&cache).unwrap().len(), 27);
+        assert!(!Path::new(&format!("{}.etag", cache)).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fetch_size_limits() {
+        let dir = test_dir("system-fetch-limits");
+        let cache = format!("{}/clipos-core", dir);
+
+        // The size from the manifest comes first
+        let system = system(&dir, Some(100));
+        let mut expected = expected(&system);
+        expected.size = 20;
+        too_large(fetch(&system, &remote(), Some(&expected)), 20);
+        assert!(!Path::new(&cache).exists());
+
+        // Then the maximum size of the package
+        let system = self::system(&dir, Some(10));
+        too_large(fetch(&system, &remote(), None), 10);
+        assert!(!Path::new(&cache).exists());
+
+        // And finally the maximum size of all payloads
+        let system = self::system(&dir, None);
+        let mut r = remote();
+        r.limits.max_payload_size = Some(26);
+        too_large(fetch(&system, &r, None), 26);
+        assert!(!Path::new(&cache).exists());
+
+        // Signatures are limited to the maximum metadata size
+        r.limits.max_metadata_size = 16;
+        too_large(fetch(&system, &r, None), 16);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_without_etag() {
+        let dir = test_dir("system-interrupted");
+        let system = system(&dir, None);
+        let pkg = &system.packages[0];
+        let cache = system.cache(pkg);
+        let etag = format!("{}.etag", cache);
+        let sig = include_bytes!("../webroot/dist/5.0.0-alpha.3/clipos-core.sig");
+        fs::write(system.cache_sig(pkg), &sig[..]).unwrap();
+
+        // The server sent no ETag and the download was cut off
+        System::save_etag(None, &etag).unwrap();
+        fs::write(&cache, b"truncated").unwrap();
+        assert_eq!(System::partial(&cache, &etag), None);
+        assert_eq!(system.staged().unwrap(), None);
+
+        // Complete downloads are validated
+        fs::remove_file(&etag).unwrap();
+        match system.staged() {
+            Err(Error::InvalidSignature { .. }) => (),
+            r => panic!("truncated download accepted: {:?}", r),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn not_intact(res: Result<()>) {
+        match res {
+            Err(Error::NotIntact { .. }) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn verify_lv_content() {
+        let dir = test_dir("system-verify-lv");
+        let system = system(&dir, None);
+        let pkg = &system.packages[0];
+        let v = Version::parse("5.0.0-alpha.3").unwrap();
+        let image: &[u8] = include_bytes!("../webroot/dist/5.0.0-alpha.3/clipos-core");
+        let sig: &[u8] = include_bytes!("../webroot/dist/5.0.0-alpha.3/clipos-core.sig");
+
+        // The size and signature of the image are kept once installed
+        fs::write(system.cache(pkg), image).unwrap();
+        fs::write(system.cache_sig(pkg), sig).unwrap();
+        system.keep_lv(pkg, &v);
+        assert!(!Path::new(&system.cache_sig(pkg)).exists());
+
+        // The LV may be larger than the image
+        let lv = &format!("{}/lv", dir);
+        let mut larger = image.to_vec();
+        larger.resize(image.len() + 100, 0);
+        fs::write(lv, &larger).unwrap();
+        system.verify_lv(pkg, &v, lv).unwrap();
+
+        let mut tampered = image.to_vec();
+        tampered[0] ^= 1;
+        fs::write(lv, &tampered).unwrap();
+        not_intact(system.verify_lv(pkg, &v, lv));
+        fs::write(lv, &image[..10]).unwrap();
+        not_intact(system.verify_lv(pkg, &v, lv));
+
+        // Nothing is recorded for versions installed by older updaters
+        fs::write(lv, image).unwrap();
+        fs::remove_file(system.kept_size(pkg, &v)).unwrap();
+        not_intact(system.verify_lv(pkg, &v, lv));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("500"), Some(500 << 20));
+        assert_eq!(parse_size(" 500M "), Some(500 << 20));
+        assert_eq!(parse_size("2g"), Some(2 << 30));
+        assert_eq!(parse_size("64k"), Some(64 << 10));
+        assert_eq!(parse_size("8s"), Some(4096));
+        assert_eq!(parse_size("27b"), Some(27));
+
+        assert_eq!(parse_size("1.5G"), Some(3 << 29));
+        assert_eq!(parse_size("0.5"), Some(1 << 19));
+        assert_eq!(parse_size(".25m"), Some(1 << 18));
+        assert_eq!(parse_size("2.G"), Some(2 << 30));
+        // Rounded up to the next byte
+        assert_eq!(parse_size("0.001k"), Some(2));
+        assert_eq!(parse_size("1.1b"), Some(2));
+
+        for invalid in &["", ".", "G", "1.2.3G", "1,5G", "1.5X", "-1G", "1 G", "16E"] {
+            assert_eq!(parse_size(invalid), None, "'{}' accepted", invalid);
+        }
+        assert_eq!(parse_size("0.0000000000000000001G"), None);
+    }
+
+    #[test]
+    fn throttle() {
+        let data = vec![42u8; 300];
+        let mut buf = [0u8; 300];
+
+        // 300 bytes at 1000 bytes per second, read a tenth of a second worth
+        // of data at a time
+        let start = Instant::now();
+        let mut throttle = Throttle::new(&data[..], Some(1000));
+        assert_eq!(throttle.read(&mut buf).unwrap(), 100);
+        let mut read = buf[..100].to_vec();
+        throttle.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        // Without limit, data is read at once
+        for rate in &[None, Some(0)] {
+            let mut throttle = Throttle::new(&data[..], *rate);
+            assert_eq!(throttle.read(&mut buf).unwrap(), 300);
+        }
+    }
+}
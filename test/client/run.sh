@@ -22,6 +22,9 @@ cleanup() {
     sudo lvremove mainvg/core_5.0.0-alpha.4 -y &> /dev/null
     sudo rm -f /mnt/efiboot/EFI/Linux/clipos-*.efi &> /dev/null
     sudo touch /mnt/efiboot/EFI/Linux/clipos-5.0.0-alpha.1.efi
+    # Reset the updater state (accepted manifest, floor, etc.)
+    sudo rm -rf /var/tmp/updater-state
+    sudo mkdir -p /var/tmp/updater-state
 }
 
 test_header() {
@@ -72,9 +75,9 @@ main() {
     fi
 
     # Default command
-    CMD="sudo ./updater -c config -r remote -t /tmp"
+    CMD="sudo ./updater -c config -r remote -t /tmp -s /var/tmp/updater-state"
     # Full debug command
-    # CMD="sudo RUST_BACKTRACE=1 ./updater -v -c config -r remote -t /tmp"
+    # CMD="sudo RUST_BACKTRACE=1 ./updater -v -c config -r remote -t /tmp -s /var/tmp/updater-state"
 
     cleanup
 
@@ -106,6 +109,65 @@ main() {
     sudo mv /mnt/efiboot/EFI/Linux/clipos-5.0.0-alpha.{3,4}.efi
     ${CMD}
     test_check
+
+    # Check only reports the available version
+    test_header "Check"
+    cleanup
+    [[ "$(${CMD} check)" == "5.0.0-alpha.3" ]]
+    [[ "$(${CMD} --output json check)" == '{"version":"5.0.0-alpha.3"}' ]]
+    echo "# OK"
+
+    # Dry run must not modify the system, the cache or the updater state
+    test_header "Dry run"
+    sudo rm -f /tmp/clipos-*
+    state="$(sudo ls -l --full-time /var/tmp/updater-state)"
+    [[ "$(${CMD} --dry-run)" == "Download and install version '5.0.0-alpha.3'" ]]
+    if sudo lvs mainvg/core_5.0.0-alpha.3 &> /dev/null; then
+        echo "LV created during dry run!"
+        exit 1
+    fi
+    if compgen -G "/tmp/clipos-*" > /dev/null; then
+        echo "Payloads downloaded during dry run!"
+        exit 1
+    fi
+    [[ "$(sudo ls -l --full-time /var/tmp/updater-state)" == "${state}" ]]
+    if ${CMD} --dry-run download; then
+        echo "Dry run accepted by download!"
+        exit 1
+    fi
+    echo "# OK"
+
+    # Stages run separately
+    test_header "Download and install"
+    ${CMD} download
+    ${CMD} --dry-run install
+    ${CMD} --dry-run | grep -q "Copy"
+    ${CMD} install
+    test_check
+
+    test_header "Status"
+    ${CMD} status
+    # Top-level fields of the pretty-printed report: the running version is
+    # unchanged until reboot, and the installed update is ready
+    status="$(${CMD} --output json status)"
+    grep -qx '  "version": "5.0.0-alpha.1",' <<< "${status}"
+    grep -qx '  "update_ready": true' <<< "${status}"
+    echo "# OK"
+
+    # Only older versions may be rolled back to, the running one is
+    # 5.0.0-alpha.1 (see vagrant/provision.sh)
+    test_header "Rollback"
+    if ${CMD} --dry-run rollback; then
+        echo "Rollback to a newer version!"
+        exit 1
+    fi
+    sudo lvrename mainvg core_5.0.0-alpha.3 core_5.0.0-alpha.0 &> /dev/null
+    sudo mv /mnt/efiboot/EFI/Linux/clipos-5.0.0-alpha.{3,0}.efi
+    [[ "$(${CMD} --dry-run rollback)" == "Rollback to version '5.0.0-alpha.0'" ]]
+    ${CMD} rollback
+    sudo lvrename mainvg core_5.0.0-alpha.0 core_5.0.0-alpha.3 &> /dev/null
+    sudo mv /mnt/efiboot/EFI/Linux/clipos-5.0.0-alpha.{0,3}.efi
+    test_check
 }
 
 main